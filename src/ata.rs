@@ -37,8 +37,48 @@ pub fn init() {
     unsafe {
         interrupts::IDT[pic::IRQ_OFFSET + 14] = interrupts::Handler::new(irq14, GateType::DInterrupt, 0);
         interrupts::IDT[pic::IRQ_OFFSET + 15] = interrupts::Handler::new(irq15, GateType::DInterrupt, 0);
+        SECTOR_COUNT = identify();
     }
-    //TODO: query maximum HD size. don't allow going over the sector limit
+}
+
+/// How many sectors the drive has (see identify)
+static mut SECTOR_COUNT: usize = 0;
+
+/// How many sectors the drive has. Reading or writing past them fails
+pub fn sector_count() -> usize { unsafe { SECTOR_COUNT } }
+
+/// Asks the drive how many sectors it has. Returns 0 if there's no (ATA) drive
+unsafe fn identify() -> usize {
+    io::outb(PORT_DHR, 0xA0); // the master drive, like setup_flags
+    io::outb(PORT_SCR, 0);
+    io::outb(PORT_SNR, 0);
+    io::outb(PORT_CLR, 0);
+    io::outb(PORT_CHR, 0);
+    io::outb(PORT_CR, 0xEC); // send the identify command
+    if io::inb(PORT_SR) == 0 {
+        return 0; // nothing's there
+    }
+    wait_for(STATUS_BSY, false);
+    // ATAPI and SATA drives set these instead of answering
+    if io::inb(PORT_CLR) != 0 || io::inb(PORT_CHR) != 0 {
+        return 0;
+    }
+    loop {
+        let status = io::inb(PORT_SR);
+        if status & STATUS_ERR != 0 {
+            return 0;
+        }
+        if status & STATUS_DRQ != 0 {
+            break;
+        }
+    }
+
+    let mut data = [0u16; 256];
+    for word in &mut data {
+        *word = io::inw(PORT_DR);
+    }
+    // Words 60 and 61 are how many sectors there are to 28 bit LBA, which is all setup_flags can do anyways
+    data[60] as usize | (data[61] as usize) << 16
 }
 
 extern "x86-interrupt" fn irq14() {
@@ -28,6 +28,7 @@ gdt:
   DATA_SEG equ .data - gdt
   USER_CODE_SEG equ .user_code - gdt
   USER_DATA_SEG equ .user_data - gdt
+  DOUBLE_FAULT_TSS_SEG equ .double_fault_tss - gdt
   GDT_ENTRIES_ADDR equ gdt
   .null: ; mandatory null descriptor
     dd 0x0
@@ -72,6 +73,10 @@ gdt:
     db 11110010b ; 1st flags, type flags
     db 11001111b ; 2nd flags, Limit (bits 16-19)
     db 0x0 ; Base (bits 24-31)
+  .tss: ; filled in by src/userspace.rs::write_tss
+    dq 0x0
+  .double_fault_tss: ; filled in by src/userspace.rs::write_double_fault_tss
+    dq 0x0
   gdt_end: ; used to calculate size of GDT descriptor
 
   gdt_descriptor:
@@ -121,4 +126,5 @@ global CODE_SEG
 global DATA_SEG
 global USER_CODE_SEG
 global USER_DATA_SEG
+global DOUBLE_FAULT_TSS_SEG
 global GDT_ENTRIES_ADDR
@@ -0,0 +1,111 @@
+use crate::{execution::ExecError, fd::IoError, fs::FileError};
+
+macro_rules! decl_errnos {
+    ( $( $(#[$doc:meta])* $name:ident = $code:literal ),* $(,)? ) => {
+        /// Why a syscall failed. Syscalls return these negated in eax (see syscall.rs), like Linux does
+        #[repr(i32)]
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        pub enum Errno {
+            $( $(#[$doc])* $name = $code, )*
+        }
+
+        impl Errno {
+            /// Returns the Errno with this (positive) code. Anything we don't know about is EINVAL
+            pub fn from_code(code: i32) -> Errno {
+                match code {
+                    $( $code => Errno::$name, )*
+                    _ => Errno::EINVAL,
+                }
+            }
+        }
+    };
+}
+
+decl_errnos!(
+    /// Not allowed
+    EPERM = 1,
+    /// No such file
+    ENOENT = 2,
+    /// No such process or thread
+    ESRCH = 3,
+    /// The disk failed
+    EIO = 5,
+    /// The arguments (e.g. exec's argv and envp) are too long
+    E2BIG = 7,
+    /// Not an executable we can run
+    ENOEXEC = 8,
+    /// The descriptor isn't open, or can't be used like that (e.g. writing to a pipe's read end)
+    EBADF = 9,
+    /// No such child process
+    ECHILD = 10,
+    /// Try again (e.g. a futex didn't hold the expected value)
+    EAGAIN = 11,
+    ENOMEM = 12,
+    /// A pointer argument isn't valid
+    EFAULT = 14,
+    /// The file is already open
+    EBUSY = 16,
+    /// The file already exists
+    EEXIST = 17,
+    /// Something in the path isn't a directory
+    ENOTDIR = 20,
+    /// The path is a directory
+    EISDIR = 21,
+    EINVAL = 22,
+    /// The file system can't hold any more files
+    ENFILE = 23,
+    /// No space left on the device
+    ENOSPC = 28,
+    /// The descriptor has no cursor to seek
+    ESPIPE = 29,
+    /// Writing to a pipe nobody reads from
+    EPIPE = 32,
+    ENAMETOOLONG = 36,
+    /// No such syscall
+    ENOSYS = 38,
+    /// The directory isn't empty
+    ENOTEMPTY = 39,
+);
+
+impl From<FileError> for Errno {
+    fn from(err: FileError) -> Errno {
+        match err {
+            FileError::TooManyFiles => Errno::ENFILE,
+            FileError::FileAlreadyExists => Errno::EEXIST,
+            FileError::FileAlreadyOpen => Errno::EBUSY,
+            FileError::FileClosed => Errno::EBADF,
+            FileError::OutOfSpace => Errno::ENOSPC,
+            FileError::FileNotFound => Errno::ENOENT,
+            FileError::PathTooLong => Errno::ENAMETOOLONG,
+            FileError::NotADirectory => Errno::ENOTDIR,
+            FileError::IsADirectory => Errno::EISDIR,
+            FileError::DirectoryNotEmpty => Errno::ENOTEMPTY,
+            FileError::InvalidPath => Errno::EINVAL,
+        }
+    }
+}
+
+impl From<IoError> for Errno {
+    fn from(err: IoError) -> Errno {
+        match err {
+            IoError::BadFd => Errno::EBADF,
+            // Like Linux does for writing to a descriptor opened read-only
+            IoError::NotSupported => Errno::EBADF,
+            IoError::NotSeekable => Errno::ESPIPE,
+            IoError::OutOfMemory => Errno::ENOMEM,
+            IoError::BrokenPipe => Errno::EPIPE,
+            IoError::File(err) => err.into(),
+            IoError::Exec(err) => err.into(),
+        }
+    }
+}
+
+impl From<ExecError> for Errno {
+    fn from(err: ExecError) -> Errno {
+        match err {
+            ExecError::File(err) => err.into(),
+            ExecError::InvalidExecutable => Errno::ENOEXEC,
+            ExecError::ArgumentsTooLong => Errno::E2BIG,
+        }
+    }
+}
@@ -1,55 +1,77 @@
-use core::{arch::asm, alloc::Layout};
+use core::mem::size_of;
 
-use alloc::alloc::dealloc;
+use alloc::{string::String, sync::Arc, vec::Vec};
 
-use crate::{paging::{self, PageFlags, PageDirectory}, io::Read};
+use crate::{heap::{USER_HEAP_START, USER_HEAP_END}, interrupts::TrapFrame, paging::{self, PageFlags, PageDirectory, HEAP_LIMIT, PAGE_SIZE}, process::Pid, vma::{self, Area, AreaData}, io::Read, fs::{File, FileError}};
 
-/// Runs the compiled code in program, starting at main_offset,
-/// and returns the error code returned by the program in EAX.
-unsafe fn enter_loaded_program(entry_point: u32, new_esp: u32, new_dir: *mut PageDirectory, prev_dir: *mut PageDirectory) {
-    // push the previous stack onto the new one (for recovery after the program's execution)
-    asm!(
-        "mov [{new_esp}], esp",
-        new_esp = in(reg) new_esp - 4 // - 4 since push subtracts 4 from esp
-    );
+/// What a program's entry point looks like. argv and envp are null terminated, and point into the
+/// System V initial stack (see build_initial_stack), which is right above the entry point's stack frame.
+/// (Programs that don't care about their arguments can simply be `fn() -> i32`)
+pub type EntryPoint = extern "C" fn(argc: usize, argv: *const *const u8, envp: *const *const u8) -> i32;
 
-    // Load start_of_program_execution into the task scheduler with our entry point -
-    // as soon as it's time to start executing our program, it'll call it
-
-    // First we push the entry point (start_of_program_execution's parameter) to our new stack
-    asm!(
-        "mov [{new_esp}], {entry_point}",
-        new_esp = in(reg) new_esp - 8, // - 8 to account for the pushed stack and the new value
-        entry_point = in(reg) entry_point
-    );
+/// Why exec failed
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExecError {
+    /// The file couldn't be opened
+    File(FileError),
+    /// The file isn't an ELF executable we can run
+    InvalidExecutable,
+    /// argv and envp don't fit on the new program's stack (see MAX_ARGS_SIZE)
+    ArgumentsTooLong,
+}
 
+/// Registers the loaded program as a new process that starts executing at entry_point, and returns its PID.
+/// stack is what build_initial_stack returned. The program's exit status is whatever its entry point returns.
+unsafe fn enter_loaded_program(entry_point: u32, stack: usize, new_dir: *mut PageDirectory, prev_dir: *mut PageDirectory) -> Pid {
+    let frame = start_frame(entry_point, stack);
     (*prev_dir).switch_to();
 
-    // Then we pretend that start_of_program_execution is the middle of an already running program,
-    // meaning the task scheduler will call it and set esp to the new stack.
-    crate::process::register(
-        new_esp - 12, // new_esp-8 to account for the pushed values
-        start_of_program_execution as unsafe fn(fn()) as u32,
-        new_dir
-    );
+    // We pretend that start_of_program_execution is the middle of an already running program,
+    // meaning the task scheduler will switch to it just like to any other.
+    crate::process::register(frame, new_dir, false)
 }
 
-unsafe fn start_of_program_execution(entry_point: fn()) {
-    crate::pic::send_eoi(0);
-    crate::userspace::enter();
-
-    entry_point(); // Jumps out of here until the end of program execution
+/// Returns a (user mode) frame that calls start_of_program_execution with entry_point and stack.
+/// Its parameters are pushed below stack, as if it was called. (stack's directory must be active)
+unsafe fn start_frame(entry_point: u32, stack: usize) -> TrapFrame {
+    let esp = stack - 12;
+    let args = esp as *mut u32;
+    *args = 0; // the return address. start_of_program_execution never returns
+    *args.add(1) = entry_point;
+    *args.add(2) = stack as u32;
+    crate::userspace::user_frame(start_of_program_execution as u32, esp as u32)
+}
 
-    // When we're done executing the program, we can simply remove it from
-    // the list of active processes and then wait for the task scheduler
-    // to run the next program. Since this process won't be a candidate anymore
-    // it won't be executed ever again and it will effectively quit.
-    crate::syscall::PicSetMask::call(0, false);
-    crate::syscall::PicSendEoi::call(0);
-    crate::syscall::UnreigsterProcess::call();
-    loop {}
+extern "C" {
+    /// Runs in user mode, like the program itself. Takes the entry point and what build_initial_stack returned
+    fn start_of_program_execution();
 }
 
+// It lives in .user_text, the only part of the kernel user programs can see (see PageDirectory::map_kernel), so it can't call
+// anything else of ours. It calls entry_point(argc, argv, envp), and exits with whatever that returns.
+// Its parent can then collect the status.
+core::arch::global_asm!(
+    ".pushsection .user_text, \"ax\"",
+    ".global start_of_program_execution",
+    "start_of_program_execution:",
+    "mov eax, [esp + 4]", // entry_point
+    "mov edx, [esp + 8]", // stack
+    "mov ecx, [edx]", // argc
+    "lea ebx, [edx + 4]", // argv
+    "lea esi, [ebx + ecx * 4 + 4]", // envp, right after argv's null
+    "push esi",
+    "push ebx",
+    "push ecx",
+    "call eax", // Jumps out of here until the end of program execution
+    "mov ebx, eax",
+    "mov eax, {exit}",
+    "int 0x80",
+    "2:",
+    "jmp 2b",
+    ".popsection",
+    exit = const crate::syscall::Syscall::Exit as u32,
+);
+
 #[repr(C)]
 struct ElfHeader {
     ident: [u8; 16],
@@ -80,104 +102,191 @@ struct ElfProgramHeader {
     align: u32,
 }
 
-// Just a random address sometime after the end of the heap and before the kernel (calculated by hand).
-// TODO: work on an actual heap that isn't hardcoded so this won't be a thing.
-static mut MEM_START: usize = 0x4_400_000;
+// What ElfHeader has to say for us to run it: a 32 bit, little endian, x86 executable
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 2;
+const EM_386: u16 = 3;
 
-pub(crate) unsafe fn run_program(program: &[u8]) {
-    let header_bytes = &program[..core::mem::size_of::<ElfHeader>()];
-    let header: &ElfHeader = unsafe { core::mem::transmute(header_bytes.as_ptr()) };
+/// ElfProgramHeader.prog_type of a segment that should be loaded into memory
+const PT_LOAD: u32 = 1;
+/// ElfProgramHeader.flags bit of a writable segment
+const PF_W: u32 = 2;
 
-    // Verify magic number
-    if header.ident[0] != 0x7F
-        || header.ident[1] != b'E'
-        || header.ident[2] != b'L'
-        || header.ident[3] != b'F'
+/// The first address after a program's stack
+const STACK_TOP: usize = 0xC000_0000;
+/// Stack pages are only allocated once they are touched, so we can afford to be generous
+const STACK_SIZE: usize = 0x100_000;
+/// How much of the stack the initial stack (see build_initial_stack) may take up, so that the program has room left to run
+const MAX_ARGS_SIZE: usize = STACK_SIZE / 4;
+
+// Auxiliary vector entry types (see build_initial_stack)
+const AT_NULL: usize = 0;
+const AT_PAGESZ: usize = 6;
+const AT_ENTRY: usize = 9;
+
+/// Loads program (an ELF executable) into a new address space. Returns the directory and the entry point.
+/// Nothing is actually mapped yet, other than the kernel.
+unsafe fn load_image(program: &[u8]) -> Result<(*mut PageDirectory, u32), ExecError> {
+    if program.len() < size_of::<ElfHeader>() {
+        return Err(ExecError::InvalidExecutable);
+    }
+    // program is just bytes, so nothing says it's aligned
+    let header: ElfHeader = (program.as_ptr() as *const ElfHeader).read_unaligned();
+
+    if header.ident[..4] != *b"\x7FELF"
+        || header.ident[4] != ELFCLASS32
+        || header.ident[5] != ELFDATA2LSB
+        || header.machine != EM_386
+        || header.prog_entry_size as usize != size_of::<ElfProgramHeader>()
     {
-        panic!("Not a valid ELF program");
+        return Err(ExecError::InvalidExecutable);
     }
 
     // Extract the program header from the raw program bytes
     let p_start = header.prog_offset as usize;
-    let p_end = header.prog_offset + (header.prog_header_len * header.prog_entry_size) as u32;
-    let p_hdr_bytes = &program[p_start..p_end as usize];
+    let p_end = p_start.checked_add(header.prog_header_len as usize * size_of::<ElfProgramHeader>())
+        .filter(|&end| end <= program.len())
+        .ok_or(ExecError::InvalidExecutable)?;
+    let p_header: Vec<ElfProgramHeader> = program[p_start..p_end]
+        .chunks_exact(size_of::<ElfProgramHeader>())
+        .map(|entry| (entry.as_ptr() as *const ElfProgramHeader).read_unaligned())
+        .collect();
 
-    let p_header_arr: *const ElfProgramHeader =
-        unsafe { core::mem::transmute(p_hdr_bytes.as_ptr()) };
-    let p_header: &[ElfProgramHeader] =
-        unsafe { core::slice::from_raw_parts(p_header_arr, header.prog_header_len as usize) };
+    // Check every segment before creating anything, so there's nothing to undo
+    for entry in p_header.iter().filter(|entry| entry.prog_type == PT_LOAD) {
+        check_segment(entry, program.len())?;
+    }
+
+    // Segments are only loaded once they are touched (see vma::handle_fault), so we have to keep our own copy of the program
+    let image: Arc<[u8]> = Arc::from(program);
 
-    let prev_dir = PageDirectory::curr();
     // Create a new page directory for this executable
     let dir = PageDirectory::new();
-    (*dir).identity_map(paging::HEAP_END + 4096, 50*1024*1024, PageFlags::RW | PageFlags::USER);
-    // We switch to the new directory for the copy inside the loop. We switch back to the old one after it ends
-    (*dir).switch_to();
 
-    // Load each entry in the program header into memory
-    for entry in p_header {
-        if entry.prog_type != 1 {
+    // Record each entry in the program header as an area of the new address space
+    for entry in &p_header {
+        if entry.prog_type != PT_LOAD {
             continue;
         }
 
-        for i in (0..entry.mem_size as usize).step_by(paging::PAGE_SIZE) { (*dir).make_page(
-            entry.virt_addr as usize + i,  // virtual address (where program expects it)
-            MEM_START + i,                  // physical address (where we allocated it)
-            PageFlags::RW | PageFlags::USER
-        ).unwrap();
+        let flags = if entry.flags & PF_W != 0 { PageFlags::RW | PageFlags::USER } else { PageFlags::USER };
+        // If mem_size>file_size, ELF dictates we zero out whatever's left, which areas do anyways
+        let data = AreaData {
+            image: image.clone(),
+            offset: entry.offset as usize,
+            len: entry.file_size as usize,
+            addr: entry.virt_addr as usize,
+        };
+        vma::add_area(dir, Area::new(entry.virt_addr as usize, entry.mem_size as usize, flags, Some(data)));
+    }
+
+    // And the program's new stack and heap
+    vma::add_area(dir, Area::new(STACK_TOP - STACK_SIZE, STACK_SIZE, PageFlags::RW | PageFlags::USER, None));
+    vma::add_area(dir, Area::new(USER_HEAP_START, USER_HEAP_END - USER_HEAP_START, PageFlags::RW | PageFlags::USER, None));
+
+    Ok((dir, header.entry))
 }
 
-        MEM_START += (entry.mem_size as usize / 0x1000 + 1) * 0x1000;
-
-        // Copy the program into memory
-        core::ptr::copy::<u8>(
-            program.as_ptr().byte_add(entry.offset as usize),
-            entry.virt_addr as *mut u8,
-            entry.file_size as usize,
-        );
-
-        // If mem_size>file_size, ELF dictates we zero out whatever's left
-        if entry.mem_size > entry.file_size {
-            core::ptr::write_bytes(
-                (entry.virt_addr + entry.file_size) as *mut u8,
-                0,
-                (entry.mem_size - entry.file_size) as usize,
-            );
-        }
+/// Makes sure a loadable segment is within the image, and only covers memory a program may have: between the kernel's heap
+/// and its own (see USER_HEAP_START), and not the kernel image (which is mapped in there)
+fn check_segment(entry: &ElfProgramHeader, image_len: usize) -> Result<(), ExecError> {
+    let (offset, file_size, mem_size) = (entry.offset as usize, entry.file_size as usize, entry.mem_size as usize);
+    if offset.checked_add(file_size).map_or(true, |end| end > image_len) || file_size > mem_size {
+        return Err(ExecError::InvalidExecutable);
     }
 
-    // Map the program's new stack
-    const STACK_SIZE: usize = 16384;
-    (*dir).identity_map(
-        MEM_START,
-        STACK_SIZE,
-        PageFlags::USER | PageFlags::RW,
-    );
-    let stack_top = MEM_START + STACK_SIZE;
-    for i in (MEM_START..stack_top).step_by(4) {
-        let ptr = i as *mut u32;
-        unsafe { *ptr = 0xDEADBEEF };
+    // Whole pages get mapped, so that's what has to fit
+    let start = entry.virt_addr as usize & !(PAGE_SIZE - 1);
+    let end = (entry.virt_addr as usize).checked_add(mem_size)
+        .and_then(|end| end.checked_add(PAGE_SIZE - 1))
+        .ok_or(ExecError::InvalidExecutable)? & !(PAGE_SIZE - 1);
+    let covers_kernel = start < paging::kernel_end() && paging::kernel_start() < end;
+    if start < HEAP_LIMIT || end > USER_HEAP_START || covers_kernel {
+        return Err(ExecError::InvalidExecutable);
     }
+    Ok(())
+}
 
-    MEM_START += STACK_SIZE;
-
-    let aligned_stack_top = (stack_top - 4) & !0xF;
-    enter_loaded_program(header.entry, aligned_stack_top as u32, dir, prev_dir);
+/// How many bytes build_initial_stack needs for argv and envp, at most (or usize::MAX if that doesn't even fit in a usize)
+fn initial_stack_size(argv: &[&str], envp: &[&str]) -> usize {
+    let strings = argv.iter().chain(envp).fold(0usize, |size, s| size.saturating_add(s.len() + 1));
+    // argc, both arrays and their nulls, and the auxiliary vector. Plus up to 16 bytes of alignment
+    let words = (argv.len() + envp.len()).saturating_add(9).saturating_mul(size_of::<usize>());
+    strings.saturating_add(words).saturating_add(16)
 }
 
-pub fn execute_file(file: &mut crate::fs::File) {
-    let buffer = {
-        let buffer = unsafe {
-            let size = file.get_metadata().size * 512;
-            let ptr = alloc::alloc::alloc(Layout::from_size_align_unchecked(size, 4096));
-            core::slice::from_raw_parts_mut(ptr, size)
-        };
-        file.read_bytes(buffer);
-        file.close();
-        buffer
+/// Builds a System V i386 initial stack at the top of the active directory's stack, and returns its address.
+/// From there upwards, it's: argc, argv[0..argc], NULL, envp[..], NULL, the auxiliary vector (ending with AT_NULL),
+/// and then the strings themselves.
+unsafe fn build_initial_stack(entry_point: u32, argv: &[String], envp: &[String]) -> usize {
+    let mut sp = STACK_TOP;
+    let mut push_str = |s: &String| {
+        sp -= s.len() + 1;
+        core::ptr::copy_nonoverlapping(s.as_ptr(), sp as *mut u8, s.len());
+        *((sp + s.len()) as *mut u8) = 0;
+        sp
     };
+    let argv_ptrs: Vec<usize> = argv.iter().map(&mut push_str).collect();
+    let envp_ptrs: Vec<usize> = envp.iter().map(&mut push_str).collect();
+
+    let mut words: Vec<usize> = Vec::with_capacity(argv.len() + envp.len() + 8);
+    words.push(argv.len());
+    words.extend(argv_ptrs);
+    words.push(0);
+    words.extend(envp_ptrs);
+    words.push(0);
+    words.extend([AT_PAGESZ, crate::paging::PAGE_SIZE, AT_ENTRY, entry_point as usize, AT_NULL, 0]);
+
+    // argc has to be 16 byte aligned
+    let stack = (sp - words.len() * core::mem::size_of::<usize>()) & !0xF;
+    core::ptr::copy_nonoverlapping(words.as_ptr(), stack as *mut usize, words.len());
+    stack
+}
+
+/// Loads program (an ELF executable) into a new address space and runs it as a new process. Returns its PID
+pub(crate) unsafe fn run_program(program: &[u8]) -> Result<Pid, ExecError> {
+    let (dir, entry) = load_image(program)?;
+    let prev_dir = PageDirectory::curr();
+
+    // We switch to the new directory to set up the stack. enter_loaded_program switches back to the old one afterwards
+    (*dir).switch_to();
+    let stack = build_initial_stack(entry, &[], &[]);
+    Ok(enter_loaded_program(entry, stack, dir, prev_dir))
+}
+
+/// Reads all of file (from its cursor on), and calls f with its contents
+fn with_contents<T>(file: &File, f: impl FnOnce(&[u8]) -> T) -> T {
+    let mut buffer = alloc::vec![0u8; file.get_metadata().len];
+    let len = file.read_bytes(&mut buffer);
+    f(&buffer[..len])
+}
+
+/// Runs the ELF executable in file as a new process. Returns its PID
+pub fn execute_file(file: &File) -> Result<Pid, ExecError> {
+    with_contents(file, |program| unsafe { run_program(program) })
+}
+
+/// Replaces the current process' program with the ELF executable at path, keeping its PID.
+/// argv and envp are passed to the new program's entry point. Only returns if that fails.
+pub(crate) fn exec(path: &str, argv: &[&str], envp: &[&str]) -> Result<(), ExecError> {
+    if initial_stack_size(argv, envp) > MAX_ARGS_SIZE {
+        return Err(ExecError::ArgumentsTooLong);
+    }
+    let file = File::open(path).map_err(ExecError::File)?;
+    let (dir, entry) = with_contents(&file, |program| unsafe { load_image(program) })?;
+
+    // The arguments live in the old address space, so we need our own copies of them before switching
+    let argv: Vec<String> = argv.iter().map(|&s| String::from(s)).collect();
+    let envp: Vec<String> = envp.iter().map(|&s| String::from(s)).collect();
+
     unsafe {
-        run_program(buffer);
-        dealloc(buffer.as_mut_ptr(), Layout::from_size_align_unchecked(2, 2));
+        (*dir).switch_to();
+        let stack = build_initial_stack(entry, &argv, &envp);
+        // replace_image never returns, so nothing would drop these otherwise
+        drop(argv);
+        drop(envp);
+        drop(file);
+
+        crate::process::replace_image(start_frame(entry, stack), dir);
     }
 }
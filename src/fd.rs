@@ -0,0 +1,214 @@
+use alloc::{sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::{execution::ExecError, fs::{File, FileError}, io::Seek, pipe::{self, PipeReader, PipeWriter}, process::{curr_fds, Pid}};
+
+/// A file descriptor: an index into the current process' FdTable
+pub type Fd = usize;
+
+/// The descriptor every process started by the kernel reads typed input from
+pub const STDIN: Fd = 0;
+/// The descriptor every process started by the kernel prints to
+pub const STDOUT: Fd = 1;
+/// Where errors go. The console as well, for processes started by the kernel
+pub const STDERR: Fd = 2;
+
+/// Descriptors are always below this, so a process can't make its table arbitrarily big (see dup2)
+pub const MAX_FDS: Fd = 256;
+
+/// Something a process has open
+#[derive(Clone)]
+pub enum Handle {
+    /// An open file. Duplicates (and children's copies) share it, along with its cursor
+    File(Arc<Mutex<File>>),
+    PipeReader(PipeReader),
+    PipeWriter(PipeWriter),
+    /// Writing prints to the screen
+    Console,
+    /// Reading returns whatever was typed
+    Keyboard,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IoError {
+    /// The descriptor isn't open
+    BadFd,
+    /// The descriptor can't be used like that (e.g. writing to a pipe's read end)
+    NotSupported,
+    /// Seeking something that has no cursor (e.g. a pipe)
+    NotSeekable,
+    /// There isn't enough memory for it (e.g. for a new pipe)
+    OutOfMemory,
+    /// Writing to a pipe nobody can read from anymore
+    BrokenPipe,
+    /// The file system refused (e.g. the file doesn't exist, or there's no room left in it)
+    File(FileError),
+    /// The file couldn't be run (e.g. it isn't an executable)
+    Exec(ExecError),
+}
+
+/// The handles a process has open, by descriptor. Shared by all of its threads, and copied into its children
+#[derive(Clone, Default)]
+pub struct FdTable {
+    handles: Vec<Option<Handle>>,
+}
+
+impl FdTable {
+    /// A table with STDIN wired to the keyboard, and STDOUT and STDERR to the console
+    pub fn standard() -> Self {
+        FdTable { handles: alloc::vec![Some(Handle::Keyboard), Some(Handle::Console), Some(Handle::Console)] }
+    }
+
+    /// Adds handle at the lowest free descriptor, and returns it
+    pub fn add(&mut self, handle: Handle) -> Fd {
+        match self.handles.iter().position(|h| h.is_none()) {
+            Some(fd) => {
+                self.handles[fd] = Some(handle);
+                fd
+            }
+            None => {
+                self.handles.push(Some(handle));
+                self.handles.len() - 1
+            }
+        }
+    }
+
+    /// Makes sure the next count calls to add won't need to allocate
+    pub fn reserve(&mut self, count: usize) -> Result<(), IoError> {
+        let free = self.handles.iter().filter(|h| h.is_none()).count();
+        self.handles.try_reserve(count.saturating_sub(free)).map_err(|_| IoError::OutOfMemory)
+    }
+
+    pub fn get(&self, fd: Fd) -> Option<&Handle> {
+        self.handles.get(fd)?.as_ref()
+    }
+
+    /// Puts handle at fd, and returns whatever was there before. Err(BadFd) if fd isn't below MAX_FDS
+    pub fn set(&mut self, fd: Fd, handle: Handle) -> Result<Option<Handle>, IoError> {
+        let len = fd.checked_add(1).filter(|&len| len <= MAX_FDS).ok_or(IoError::BadFd)?;
+        if len > self.handles.len() {
+            self.handles.resize(len, None);
+        }
+        Ok(self.handles[fd].replace(handle))
+    }
+
+    /// Takes fd out of the table. The handle is only closed once it's dropped
+    pub fn remove(&mut self, fd: Fd) -> Option<Handle> {
+        self.handles.get_mut(fd)?.take()
+    }
+}
+
+/// Returns a copy of the current process' handle at fd. We never hold the table while using a handle (which might block)
+fn get(fd: Fd) -> Result<Handle, IoError> {
+    curr_fds().lock().get(fd).cloned().ok_or(IoError::BadFd)
+}
+
+/// Calls f with a copy of the current process' handle at fd, for using it in ways that might block. The copy is kept by the
+/// current thread (see Process::in_use) rather than on its stack, since if the process exits while we're blocked, nothing on
+/// its stack is ever dropped, and e.g. a pipe would never find out this end was closed
+fn with_handle<T>(fd: Fd, f: impl FnOnce(&Handle) -> Result<T, IoError>) -> Result<T, IoError> {
+    let handle = crate::process::set_in_use(get(fd)?);
+    // It stays where it is until take_in_use
+    let result = f(unsafe { &*handle });
+    drop(crate::process::take_in_use());
+    result
+}
+
+/// Opens the file at path (creating it first if create is set and it doesn't exist), and returns its descriptor
+pub(crate) fn open(path: &str, create: bool) -> Result<Fd, IoError> {
+    let file = match File::open(path) {
+        Err(FileError::FileNotFound) if create => File::create(path),
+        result => result,
+    };
+    let file = file.map_err(IoError::File)?;
+    Ok(curr_fds().lock().add(Handle::File(Arc::new(Mutex::new(file)))))
+}
+
+/// Creates a pipe, and opens its read end and write end (in that order) as out
+pub(crate) fn pipe(out: &mut [Fd; 2]) -> Result<(), IoError> {
+    let (reader, writer) = pipe::new()?;
+    let fds = curr_fds();
+    let mut fds = fds.lock();
+    fds.reserve(2)?;
+    *out = [fds.add(Handle::PipeReader(reader)), fds.add(Handle::PipeWriter(writer))];
+    Ok(())
+}
+
+/// Reads up to buffer.len() bytes from fd. Pipes and the keyboard block until at least one is available. Ok(0) means EOF
+pub(crate) fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize, IoError> {
+    with_handle(fd, |handle| match handle {
+        Handle::File(file) => Ok(file.lock().read(buffer)),
+        Handle::PipeReader(reader) => Ok(reader.0.read(buffer)),
+        Handle::Keyboard => Ok(crate::keyboard::read_input(buffer)),
+        _ => Err(IoError::NotSupported),
+    })
+}
+
+/// Writes data to fd, blocking if it has to. Returns how much was written
+pub(crate) fn write(fd: Fd, data: &[u8]) -> Result<usize, IoError> {
+    with_handle(fd, |handle| match handle {
+        Handle::File(file) => file.lock().write(data).map_err(IoError::File),
+        Handle::PipeWriter(writer) => writer.0.write(data),
+        Handle::Console => {
+            crate::vga_console::CONSOLE.lock().write_text(data);
+            Ok(data.len())
+        }
+        _ => Err(IoError::NotSupported),
+    })
+}
+
+/// Moves fd's cursor to pos (from the start of the file). Only files have one
+pub(crate) fn seek(fd: Fd, pos: usize) -> Result<(), IoError> {
+    match get(fd)? {
+        Handle::File(file) => {
+            file.lock().seek(pos);
+            Ok(())
+        }
+        _ => Err(IoError::NotSeekable),
+    }
+}
+
+/// Closes fd. Whatever it refers to goes away once no descriptor (of any process) refers to it anymore
+pub(crate) fn close(fd: Fd) -> Result<(), IoError> {
+    let handle = curr_fds().lock().remove(fd);
+    // Closing the last end of a pipe wakes whoever is waiting on the other one, so it happens after we let go of the table
+    handle.map(drop).ok_or(IoError::BadFd)
+}
+
+/// Opens another descriptor (the lowest free one) for whatever fd refers to
+pub(crate) fn dup(fd: Fd) -> Result<Fd, IoError> {
+    let fds = curr_fds();
+    let mut fds = fds.lock();
+    let handle = fds.get(fd).cloned().ok_or(IoError::BadFd)?;
+    Ok(fds.add(handle))
+}
+
+/// Makes new_fd refer to whatever fd does, closing whatever it referred to before. new_fd has to be below MAX_FDS
+pub(crate) fn dup2(fd: Fd, new_fd: Fd) -> Result<Fd, IoError> {
+    if new_fd >= MAX_FDS {
+        return Err(IoError::BadFd);
+    }
+    let fds = curr_fds();
+    let mut fds = fds.lock();
+    let handle = fds.get(fd).cloned().ok_or(IoError::BadFd)?;
+    let closed = if fd != new_fd { fds.set(new_fd, handle)? } else { None };
+    drop(fds);
+    drop(closed);
+    Ok(new_fd)
+}
+
+/// Runs the ELF executable open at fd as a new process, and returns its PID
+pub(crate) fn execute(fd: Fd) -> Result<Pid, IoError> {
+    match get(fd)? {
+        Handle::File(file) => {
+            let mut file = file.lock();
+            // The whole file is the program, wherever the cursor is
+            let pos = file.get_cursor_position();
+            file.seek(0);
+            let pid = crate::execution::execute_file(&file);
+            file.seek(pos);
+            pid.map_err(IoError::Exec)
+        }
+        _ => Err(IoError::NotSupported),
+    }
+}
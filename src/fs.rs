@@ -1,47 +1,314 @@
 use core::alloc::Layout;
 use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::{alloc::alloc, vec::Vec, string::{String, ToString}};
 use spin::{Lazy, Mutex};
 
 use crate::io;
 
-/// The number of sectors the Header struct should take up.
-pub const HEADER_SECTORS: usize = 2;
+/// The number of sectors the Header struct should take up. Files start right after it
+pub const HEADER_SECTORS: usize = 3;
+/// How many of the header's sectors the entries take up (the last one is the free sector map)
+const ENTRY_SECTORS: usize = 2;
 /// The maximum possible number of files
-pub const MAX_FILES: usize = HEADER_SECTORS * 512 / size_of::<FileMetadata>();
+pub const MAX_FILES: usize = ENTRY_SECTORS * 512 / size_of::<FileMetadata>();
+/// How many sectors the free sector map keeps track of (one bit each), which is as big as a drive can get: the map takes up
+/// the header's last sector. Files can't go past them, even if the drive is bigger (and they can't go past the end of a
+/// smaller one either, see sector_limit)
+pub const DISK_SECTORS: usize = 512 * 8;
 
-pub const MAX_PATH_LENGTH: usize = 32;
+/// The maximum length of a single file or directory name (a path can have as many of them as it likes)
+pub const MAX_NAME_LENGTH: usize = 28;
+
+/// Marks a header whose entries form a tree (see FileMetadata::parent), that has a free sector map (see Header::used),
+/// and whose files' lengths are in bytes (see FileMetadata::len). Drives from before that have no magic at all, and get
+/// upgraded when they're read
+const FS_MAGIC: u32 = 0x0551D125;
+
+/// How often (in milliseconds) the header is written back to disk, if it changed (see flush_thread)
+const FLUSH_INTERVAL: u32 = 1000;
+
+/// An entry's index in the header. It identifies a file or directory for as long as it exists, like an inode number would
+pub type Inode = usize;
+/// The root directory. It isn't an entry itself, it's just the parent of the top level ones
+pub const ROOT: Inode = usize::MAX;
 
 bitflags::bitflags! {
     #[derive(Clone, Copy)]
     pub struct FileFlags: u8 {
         const OPENED = 1;
         const DELETED = 2;
+        /// The entry is a directory, which other entries can be in. It has no contents of its own
+        const DIRECTORY = 4;
     }
 }
 
 /// The first sectors of a hard drive using our file system are a list of FileMetadatas.
-/// We use them to find out where each file is. (to map each path to its contents)
+/// We use them to find out where each file is, and which directory it's in.
 #[repr(packed)]
 #[derive(Clone, Copy)]
 pub struct FileMetadata {
-    /// a string that contains the path of each file. padded with nulls to the right.
-    pub path: [u8; MAX_PATH_LENGTH],
-    /// the index of the of the file content's first sector
+    /// The entry's name within its directory, padded with nulls to the right
+    pub name: [u8; MAX_NAME_LENGTH],
+    /// The directory the entry is in: either another entry, or ROOT
+    pub parent: Inode,
+    /// the index of the of the file content's first sector (meaningless while len is 0)
     pub sector: usize,
-    /// how many sectors does this file take up?
-    pub size: usize,
+    /// how many bytes long the file is. It takes up just enough sectors for them (see sectors)
+    pub len: usize,
     /// flags for this file
     pub flags: FileFlags,
 }
 
-/// The struct that sits at the top of the hard drive, containing the FileMetadata maps.
+impl FileMetadata {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(MAX_NAME_LENGTH);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    /// Sets the name, cutting it short if it's longer than MAX_NAME_LENGTH
+    fn set_name(&mut self, name: &str) {
+        let name = truncate_name(name);
+        self.name = [0; MAX_NAME_LENGTH];
+        self.name[..name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    pub fn is_dir(&self) -> bool { self.flags.contains(FileFlags::DIRECTORY) }
+
+    fn is_deleted(&self) -> bool { self.flags.contains(FileFlags::DELETED) }
+
+    /// How many sectors the file takes up
+    pub fn sectors(&self) -> usize { self.len.div_ceil(512) }
+}
+
+/// Returns as much of name as fits in MAX_NAME_LENGTH bytes
+fn truncate_name(name: &str) -> &str {
+    let mut len = usize::min(name.len(), MAX_NAME_LENGTH);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+    &name[..len]
+}
+
+/// The struct that sits at the top of the hard drive, containing the FileMetadata maps and which sectors are free.
 #[repr(packed)]
 pub struct Header {
     first_null: usize, // the index (in entries, the next field) of the first null FileMetadata.
     entries: [FileMetadata; MAX_FILES],
-    _padding: [u8; 1024 - 988], // align to 512 bytes
+    /// FS_MAGIC, unless the header is from an older version of the file system (see upgrade)
+    magic: u32,
+    // fills up the entries' sectors, so that the map starts on a sector of its own
+    _padding: [u8; ENTRY_SECTORS * 512 - size_of::<usize>() - MAX_FILES * size_of::<FileMetadata>() - size_of::<u32>()],
+    /// One bit per sector, which is set if the sector is in use (by the header itself, or by a file)
+    used: [u8; DISK_SECTORS / 8],
+}
+
+// The header is read and written as whole sectors
+const _: () = assert!(size_of::<Header>() == HEADER_SECTORS * 512);
+
+impl Header {
+    /// Returns the entry called name in the directory dir, if there is one
+    fn find(&self, dir: Inode, name: &str) -> Option<Inode> {
+        (0..self.first_null).find(|&i| {
+            let entry = &self.entries[i];
+            !entry.is_deleted() && entry.parent == dir && entry.name() == name
+        })
+    }
+
+    /// Makes sure inode is a directory that still exists
+    fn check_dir(&self, inode: Inode) -> Result<(), FileError> {
+        if inode == ROOT {
+            return Ok(());
+        }
+        let entry = &self.entries[inode];
+        if entry.is_deleted() {
+            Err(FileError::FileNotFound)
+        } else if !entry.is_dir() {
+            Err(FileError::NotADirectory)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Finds whatever path refers to. Absolute paths start at the root, and relative ones at cwd.
+    /// . is the directory a name is in, and .. is its parent (the root is its own parent)
+    fn lookup(&self, cwd: Inode, path: &str) -> Result<Inode, FileError> {
+        let mut curr = if path.starts_with('/') { ROOT } else { cwd };
+        for name in path.split('/') {
+            // Whatever comes before a / has to be a directory, even if the name after it is empty (e.g. "file/")
+            self.check_dir(curr)?;
+            curr = match name {
+                "" | "." => curr,
+                ".." if curr == ROOT => ROOT,
+                ".." => self.entries[curr].parent,
+                _ => self.find(curr, name).ok_or(FileError::FileNotFound)?,
+            };
+        }
+        Ok(curr)
+    }
+
+    /// Splits path into the directory its last name is in (which has to exist), and that name (which doesn't have to)
+    fn lookup_parent<'p>(&self, cwd: Inode, path: &'p str) -> Result<(Inode, &'p str), FileError> {
+        let path = path.trim_end_matches('/');
+        let (dir_path, name) = match path.rfind('/') {
+            Some(i) => (&path[..=i], &path[i + 1..]),
+            None => ("", path),
+        };
+        let dir = self.lookup(cwd, dir_path)?;
+        self.check_dir(dir)?;
+
+        if name.is_empty() || name == "." || name == ".." || name.contains('\0') {
+            return Err(FileError::InvalidPath);
+        }
+        if name.len() > MAX_NAME_LENGTH {
+            return Err(FileError::PathTooLong);
+        }
+        Ok((dir, name))
+    }
+
+    /// Adds a new entry called name in the directory dir, and returns it.
+    /// It doesn't take up any sectors yet, files get them once they're written to (see resize)
+    fn add(&mut self, dir: Inode, name: &str, flags: FileFlags) -> Result<Inode, FileError> {
+        if self.find(dir, name).is_some() {
+            return Err(FileError::FileAlreadyExists);
+        }
+
+        // Reuse a deleted entry if there is one. Deleted files that are still open can't be reused yet, since their File
+        // still refers to the entry (and removed directories can't be anyone's working directory, see rmdir)
+        let reusable = self.entries[..self.first_null].iter()
+            .position(|e| e.is_deleted() && !e.flags.contains(FileFlags::OPENED));
+        let inode = match reusable {
+            Some(inode) => inode,
+            None => self.next_unused()?,
+        };
+        self.init_entry(inode, dir, name, flags);
+        Ok(inode)
+    }
+
+    /// Takes the first entry that was never used, and returns it
+    fn next_unused(&mut self) -> Result<Inode, FileError> {
+        if self.first_null == self.entries.len() {
+            return Err(FileError::TooManyFiles);
+        }
+        self.first_null += 1;
+        Ok(self.first_null - 1)
+    }
+
+    /// Makes inode an empty entry called name in the directory dir
+    fn init_entry(&mut self, inode: Inode, dir: Inode, name: &str, flags: FileFlags) {
+        // update the metadata in memory
+        let mut entry = FileMetadata { name: [0; MAX_NAME_LENGTH], parent: dir, sector: 0, len: 0, flags };
+        entry.set_name(name);
+        self.entries[inode] = entry;
+
+        // update it on disk
+        mark_header_dirty();
+    }
+
+    /// The entries in the directory dir
+    fn children(&self, dir: Inode) -> impl Iterator<Item = &FileMetadata> {
+        self.entries[..self.first_null].iter().filter(move |e| !e.is_deleted() && e.parent == dir)
+    }
+
+    fn is_used(&self, sector: usize) -> bool {
+        self.used[sector / 8] & (1 << (sector % 8)) != 0
+    }
+
+    /// Marks count sectors from start as used or free. Sectors past the end of the map aren't kept track of
+    fn set_used(&mut self, start: usize, count: usize, used: bool) {
+        for sector in start..usize::min(start.saturating_add(count), DISK_SECTORS) {
+            if used {
+                self.used[sector / 8] |= 1 << (sector % 8);
+            } else {
+                self.used[sector / 8] &= !(1 << (sector % 8));
+            }
+        }
+        mark_header_dirty();
+    }
+
+    /// Are the count sectors from start all free (and on the drive)?
+    fn is_free(&self, start: usize, count: usize) -> bool {
+        start.checked_add(count).is_some_and(|end| end <= sector_limit()) && (start..start + count).all(|s| !self.is_used(s))
+    }
+
+    /// Finds count free sectors in a row (the first ones there are), marks them as used and returns the first one
+    fn allocate(&mut self, count: usize) -> Result<usize, FileError> {
+        let mut start = HEADER_SECTORS;
+        while count <= sector_limit().saturating_sub(start) {
+            // Anything that starts before the last used sector in the way won't fit either
+            match (start..start + count).rfind(|&s| self.is_used(s)) {
+                Some(used) => start = used + 1,
+                None => {
+                    self.set_used(start, count, true);
+                    return Ok(start);
+                }
+            }
+        }
+        Err(FileError::OutOfSpace)
+    }
+
+    /// Makes the file inode len bytes long, keeping as much of its contents as fits. Anything new reads as zeros.
+    /// It grows in place if the sectors after it are free, and moves to wherever there's room otherwise
+    fn resize(&mut self, inode: Inode, len: usize) -> Result<(), FileError> {
+        let old = self.entries[inode];
+        let (sector, old_len, old_size) = (old.sector, old.len, old.sectors());
+        let size = len.div_ceil(512);
+        if size <= old_size {
+            self.set_used(sector + size, old_size - size, false);
+        } else if self.is_free(sector + old_size, size - old_size) {
+            self.set_used(sector + old_size, size - old_size, true);
+            zero_sectors(sector + old_size, size - old_size);
+        } else {
+            let new_sector = self.allocate(size)?;
+            copy_sectors(sector, new_sector, old_size);
+            zero_sectors(new_sector + old_size, size - old_size);
+            self.set_used(sector, old_size, false);
+            self.entries[inode].sector = new_sector;
+        }
+        // New sectors are zeroed above, but the rest of the old last one might still have whatever was cut off from it
+        if len > old_len && old_len % 512 != 0 {
+            zero_tail(self.entries[inode].sector + old_len / 512, old_len % 512);
+        }
+        self.entries[inode].len = len;
+        mark_header_dirty();
+        Ok(())
+    }
+}
+
+/// How many sectors files can use: the drive's, up to as many as the free sector map keeps track of
+fn sector_limit() -> usize {
+    usize::min(crate::ata::sector_count(), DISK_SECTORS)
+}
+
+/// Copies count sectors from src to dst, one at a time
+fn copy_sectors(src: usize, dst: usize, count: usize) {
+    let mut buffer = [0u8; 512];
+    for i in 0..count {
+        crate::syscall::ReadSectors::call((src + i) as u32, buffer.as_mut_ptr(), 1).unwrap();
+        crate::syscall::WriteSectors::call((dst + i) as u32, buffer.as_ptr(), 1).unwrap();
+    }
+}
+
+/// Fills sector with zeros from the byte offset from on
+fn zero_tail(sector: usize, from: usize) {
+    let mut buffer = [0u8; 512];
+    crate::syscall::ReadSectors::call(sector as u32, buffer.as_mut_ptr(), 1).unwrap();
+    buffer[from..].fill(0);
+    crate::syscall::WriteSectors::call(sector as u32, buffer.as_ptr(), 1).unwrap();
+}
+
+/// Fills count sectors from start with zeros, so a file never sees what a deleted one left there
+fn zero_sectors(start: usize, count: usize) {
+    let buffer = [0u8; 512];
+    for sector in start..start + count {
+        crate::syscall::WriteSectors::call(sector as u32, buffer.as_ptr(), 1).unwrap();
+    }
+}
+
+/// Where relative paths start from: the current process' working directory (see chdir), or the root for the kernel
+fn cwd() -> Inode {
+    if crate::process::has_loaded_processes() { crate::process::curr_cwd() } else { ROOT }
 }
 
 pub struct File {
@@ -49,7 +316,7 @@ pub struct File {
     ptr: usize
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum FileError {
     TooManyFiles,
     FileAlreadyExists,
@@ -57,104 +324,60 @@ pub enum FileError {
     FileClosed,
     OutOfSpace,
     FileNotFound,
+    /// One of the path's names is longer than MAX_NAME_LENGTH (or the path doesn't fit where it was asked for)
     PathTooLong,
+    /// Something in the path that has to be a directory isn't one
+    NotADirectory,
+    /// The path is a directory, but a file was expected
+    IsADirectory,
+    /// Only empty directories can be removed
+    DirectoryNotEmpty,
+    /// The path can't be created or removed (e.g. it ends with .. or is the root)
+    InvalidPath,
 }
 
 impl File {
     fn from_index(index: usize) -> File { File { index, ptr: 0 } }
 
+    /// Opens the file at path (relative to the current directory, unless it starts with a /)
     pub fn open(path: &str) -> Result<File, FileError> {
+        let cwd = cwd();
         let mut header = crate::syscall::get_fs_header().lock();
 
-        // search for a file with the same path as the parameter
-        for i in 0..header.entries.len() {
-            let file = &mut header.entries[i];
-            if file.flags.contains(FileFlags::DELETED) { continue; }
-            // convert the path (a null terminated [u8]) to a rust string
-            let file_path = unsafe {
-                core::str::from_utf8_unchecked(
-                    core::ffi::CStr::from_ptr(file.path.as_ptr() as *const _).to_bytes(),
-                )
-            };
-            if file_path == path {
-                // found it!
-                if file.flags.contains(FileFlags::OPENED) {
-                    return Err(FileError::FileAlreadyOpen);
-                }
-                file.flags.set(FileFlags::OPENED, true);
-                return Ok(File::from_index(i));
-            }
+        let index = header.lookup(cwd, path)?;
+        if index == ROOT || header.entries[index].is_dir() {
+            return Err(FileError::IsADirectory);
+        }
+        let file = &mut header.entries[index];
+        if file.flags.contains(FileFlags::OPENED) {
+            return Err(FileError::FileAlreadyOpen);
         }
-        Err(FileError::FileNotFound)
+        file.flags.set(FileFlags::OPENED, true);
+        Ok(File::from_index(index))
     }
 
+    /// Creates an empty file at path (relative to the current directory, unless it starts with a /), and opens it.
+    /// The directory it's in has to exist already
     pub fn create(path: &str) -> Result<File, FileError> {
+        let cwd = cwd();
         let mut header = crate::syscall::get_fs_header().lock();
 
-        if path.len() >= MAX_PATH_LENGTH {
-            return Err(FileError::PathTooLong);
-        }
-        if header.first_null >= header.entries.len() {
-            return Err(FileError::TooManyFiles);
-        }
-
-        // Make sure that path doesn't already exist
-        for i in 0..header.entries.len() {
-            let file = &mut header.entries[i];
-            if file.flags.contains(FileFlags::DELETED) { continue; }
-            // convert the path (a null terminated [u8]) to a rust string
-            let file_path = unsafe {
-                core::str::from_utf8_unchecked(
-                    core::ffi::CStr::from_ptr(file.path.as_ptr() as *const _).to_bytes(),
-                )
-            };
-            if file_path == path {
-                return Err(FileError::FileAlreadyExists);
-            }
-        }
-
-        // header.first_null is the index of the file we're going to create.
-        // in order to figure out which sector we should write to,
-        // we simply add one sector to the previous file's sector.
-        // TODO: this assumes files are never deleted.
-        let addr: usize = if header.first_null > 0 {
-            let prev = header.entries[header.first_null-1];
-            prev.sector + prev.size
-        } else {
-            // or, if this is the first file, use the first available sector.
-            HEADER_SECTORS + 1
-        };
-
-        // pad the path with nulls to the right
-        let mut padded_path: [u8; MAX_PATH_LENGTH] = [0; MAX_PATH_LENGTH];
-        let len = path.len();
-        padded_path[..len].copy_from_slice(path.as_bytes());
-
-        // update the metadata in memory
-        let first_null = header.first_null;
-        header.entries[first_null] = FileMetadata {
-            path: padded_path,
-            sector: addr,
-            size: 1,
-            flags: FileFlags::empty()
-        };
-        header.first_null += 1;
-
-        // update it on disk
-        update_header(&header);
-
-        Ok(File::from_index(first_null))
+        let (dir, name) = header.lookup_parent(cwd, path)?;
+        let index = header.add(dir, name, FileFlags::OPENED)?;
+        Ok(File::from_index(index))
     }
 
+    /// Deletes the file, and frees its sectors for other files to use
     pub fn delete(&mut self) -> Result<(), FileError>{
         let mut header = crate::syscall::get_fs_header().lock();
-        let metadata = &mut header.entries[self.index];
+        let metadata = header.entries[self.index];
 
         if !metadata.flags.contains(FileFlags::OPENED) {
             return Err(FileError::FileClosed);
         }
-        metadata.flags.set(FileFlags::DELETED, true);
-        update_header(&header);
+        header.resize(self.index, 0)?;
+        header.entries[self.index].flags.set(FileFlags::DELETED, true);
+        mark_header_dirty();
         Ok(())
     }
 
@@ -163,10 +386,69 @@ impl File {
         crate::syscall::get_fs_header().lock().entries[self.index]
     }
 
-    pub fn set_size(&mut self, val: usize) {
-        let mut header = crate::syscall::get_fs_header().lock();
-        header.entries[self.index].size = val;
-        update_header(&header);
+    /// Makes the file val bytes long. Whatever doesn't fit anymore is cut off, and anything new is zeroed
+    pub fn set_size(&mut self, val: usize) -> Result<(), FileError> {
+        crate::syscall::get_fs_header().lock().resize(self.index, val)
+    }
+
+    /// Reads up to buffer.len() bytes from pos on, stopping at the end of the file. Returns how many bytes were read
+    fn read_at(&self, pos: usize, buffer: &mut [u8]) -> usize {
+        let md = self.get_metadata();
+        let count = usize::min(buffer.len(), md.len.saturating_sub(pos));
+        let mut sector = [0u8; 512];
+        let mut done = 0;
+        while done < count {
+            let offset = pos + done;
+            crate::syscall::ReadSectors::call((md.sector + offset / 512) as u32, sector.as_mut_ptr(), 1).unwrap();
+            let len = usize::min(512 - offset % 512, count - done);
+            buffer[done..done + len].copy_from_slice(&sector[offset % 512..offset % 512 + len]);
+            done += len;
+        }
+        count
+    }
+
+    /// Reads up to buffer.len() bytes from the cursor on (stopping at the end of the file), and moves the cursor past them.
+    /// Returns how many bytes were read
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let count = self.read_at(self.ptr, buffer);
+        self.ptr += count;
+        count
+    }
+
+    /// Writes data at the cursor, and moves the cursor past it. The file grows if it goes past the end. If there's no room
+    /// on the disk for that, only what fits in the sectors the file already has is written. Returns how many bytes were
+    /// written, or OutOfSpace if not even one fits
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FileError> {
+        let md = self.get_metadata();
+        if md.is_deleted() {
+            return Err(FileError::FileNotFound);
+        }
+        let end = self.ptr.saturating_add(data.len());
+        if end > md.len && self.set_size(end).is_err() {
+            // Growing within the sectors it has always works
+            let _ = self.set_size(usize::min(end, md.sectors() * 512));
+        }
+        let md = self.get_metadata();
+        let count = usize::min(data.len(), md.len.saturating_sub(self.ptr));
+        if count == 0 && !data.is_empty() {
+            return Err(FileError::OutOfSpace);
+        }
+        let mut sector = [0u8; 512];
+        let mut done = 0;
+        while done < count {
+            let offset = self.ptr + done;
+            let lba = (md.sector + offset / 512) as u32;
+            let len = usize::min(512 - offset % 512, count - done);
+            // Only part of the sector changes, so the rest of it has to be read first
+            if len < 512 {
+                crate::syscall::ReadSectors::call(lba, sector.as_mut_ptr(), 1).unwrap();
+            }
+            sector[offset % 512..offset % 512 + len].copy_from_slice(&data[done..done + len]);
+            crate::syscall::WriteSectors::call(lba, sector.as_ptr(), 1).unwrap();
+            done += len;
+        }
+        self.ptr += count;
+        Ok(count)
     }
 
     pub fn close(&mut self) {
@@ -178,7 +460,7 @@ impl File {
         }
         header.entries[self.index].flags.set(FileFlags::OPENED, false);
         self.index = MAX_FILES+1; // mark this reference as invalid
-        update_header(&header);
+        mark_header_dirty();
     }
 }
 
@@ -198,62 +480,30 @@ impl io::Seek for File {
     }
 }
 
+// Neither of these moves the cursor. Past the end of the file, there's nothing to read (and read_byte returns 0)
 impl io::Read for File {
     fn read_byte(&self) -> u8 {
-        let sector_offset = self.ptr / 512; // the sector our byte is in
-        let mut buffer = [0u8; 512]; // we have to read the whole sector, even for just one byte
-        let md = self.get_metadata();
-        crate::syscall::ReadSectors::call((md.sector + sector_offset) as u32, buffer.as_mut_ptr(), 1);
-
-        buffer[self.ptr % 512]
+        let mut byte = [0u8];
+        self.read_at(self.ptr, &mut byte);
+        byte[0]
     }
 
     fn read_bytes(&self, buffer: &mut [u8]) -> usize {
-        let md = self.get_metadata();
-        let count = usize::min(md.size * 512, buffer.len());
-
-        let sector_a = self.ptr / 512; // the sector offset of the first byte
-        let sector_b = (self.ptr + count) / 512; // the sector offset of the last byte
-        let mut sector_count = sector_b - sector_a; // the number of sectors we'll read
-        // If the read amount doesn't exactly fit within N sectors, we need to read one extra sector
-        if self.ptr % 512 != 0 || count % 512 != 0 {
-            sector_count += 1;
-        }
-        crate::syscall::ReadSectors::call((md.sector + sector_a) as u32, buffer.as_mut_ptr(), sector_count);
-        count
+        self.read_at(self.ptr, buffer)
     }
 }
 
+// Both of these grow the file like File::write does, and drop whatever doesn't fit on the disk
 impl io::Write for File {
     fn write_byte(&mut self, byte: u8) {
-        let sector_offset = self.ptr / 512; // the sector our byte is in
-        let mut buffer = [0u8; 512]; // we have to write over the whole sector, even for just one byte
-        // in order to not overwrite anything else, read this sector first
-        let md = self.get_metadata();
-        crate::syscall::ReadSectors::call((md.sector + sector_offset) as u32, buffer.as_mut_ptr(), 1);
-
-        // set the byte
-        buffer[self.ptr % 512] = byte;
-
-        // update the sector on disk
-        crate::syscall::WriteSectors::call((md.sector + sector_offset) as u32, buffer.as_ptr(), 1);
+        // Only write_bytes moves the cursor
+        let ptr = self.ptr;
+        let _ = self.write(&[byte]);
+        self.ptr = ptr;
     }
 
     fn write_bytes(&mut self, bytes: &[u8]) {
-        //todo: resizing :(
-        let sector_a = self.ptr / 512; // the first byte's sector
-        let sector_b = (self.ptr + bytes.len()) / 512; // the last byte's sector
-        let sector_count = sector_b - sector_a + 1;
-
-        // In case ptr currently points at the middle of a sector, we want to not override everything before it,
-        // so we read the final sector
-        if self.ptr % 512 != 0 {
-            //TODO
-        }
-        let md = self.get_metadata();
-
-        // update the sectors on disk
-        crate::syscall::WriteSectors::call((md.sector + sector_a) as u32, bytes.as_ptr(), sector_count);
+        let _ = self.write(bytes);
     }
 }
 
@@ -261,8 +511,15 @@ fn read_header() -> Mutex<&'static mut Header> {
     unsafe {
         let ptr = alloc(Layout::from_size_align_unchecked(HEADER_SECTORS * 512, 4));
         let header: &mut Header;
-        crate::syscall::ReadSectors::call(0, ptr, HEADER_SECTORS);
+        crate::syscall::ReadSectors::call(0, ptr, HEADER_SECTORS).unwrap();
         header = core::mem::transmute(ptr);
+        if header.magic != FS_MAGIC {
+            upgrade(header);
+        }
+        // Nothing is open yet. Whatever was when the header was last written (e.g. before a crash) isn't anymore
+        for i in 0..header.first_null {
+            header.entries[i].flags.set(FileFlags::OPENED, false);
+        }
         Mutex::new(header)
     }
 }
@@ -272,31 +529,226 @@ fn read_header() -> Mutex<&'static mut Header> {
 fn update_header(header: &Header) {
     unsafe {
         // we transmute header to be a byte array, and we use *& in order to get the data behind the mutex.
-        crate::syscall::WriteSectors::call(0, core::mem::transmute(header), HEADER_SECTORS);
+        crate::syscall::WriteSectors::call(0, core::mem::transmute(header), HEADER_SECTORS).unwrap();
+    }
+}
+
+/// Set whenever the header changes, until flush_thread writes it to disk
+static HEADER_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Has the header written to disk soon. Batching the writes means a bunch of changes in a row only cost one
+#[inline]
+fn mark_header_dirty() {
+    HEADER_DIRTY.store(true, Ordering::Release);
+}
+
+/// Runs as a kernel thread (see kernel_main), writing the header to disk whenever it changed
+pub(crate) fn flush_thread() {
+    loop {
+        crate::syscall::Sleep::call(FLUSH_INTERVAL);
+        // Anything that changes after this sets it again, so it's never lost
+        if HEADER_DIRTY.swap(false, Ordering::AcqRel) {
+            // Kernel threads run with interrupts enabled, and HEADER can't be held while they are (see HEADER)
+            unsafe { core::arch::asm!("cli"); }
+            update_header(&HEADER.lock());
+            unsafe { core::arch::asm!("sti"); }
+        }
     }
 }
 
-pub(crate) fn dir(root: &String, folders: &mut Vec<String>, files: &mut Vec<FileMetadata>) {
+/// Lists the directory root: the names of the directories in it go in folders, and the files in it go in files
+pub(crate) fn dir(root: &str, folders: &mut Vec<String>, files: &mut Vec<FileMetadata>) -> Result<(), FileError> {
+    let cwd = cwd();
     let header = crate::syscall::get_fs_header().lock();
+    let dir = header.lookup(cwd, root)?;
+    header.check_dir(dir)?;
 
-    for file in &header.entries {
-        if !file.path.starts_with(root.as_bytes()) || file.flags.contains(FileFlags::DELETED) {
-            continue;
+    for entry in header.children(dir) {
+        if entry.is_dir() {
+            folders.push(entry.name().to_string());
+        } else {
+            files.push(*entry);
+        }
+    }
+    Ok(())
+}
+
+/// One of the entries Readdir returns
+#[derive(Clone, Copy, Default, Debug)]
+pub struct DirEntry {
+    /// Padded with nulls to the right, like FileMetadata::name
+    pub name: [u8; MAX_NAME_LENGTH],
+    pub is_dir: bool,
+    /// How many bytes long the file is. 0 for directories
+    pub size: usize,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(MAX_NAME_LENGTH);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+}
+
+/// Creates an empty directory at path. Whatever directory it's in has to exist already
+pub(crate) fn mkdir(path: &str) -> Result<(), FileError> {
+    let cwd = cwd();
+    let mut header = crate::syscall::get_fs_header().lock();
+    let (dir, name) = header.lookup_parent(cwd, path)?;
+    header.add(dir, name, FileFlags::DIRECTORY)?;
+    Ok(())
+}
+
+/// Removes the directory at path, which has to be empty, and not any process' working directory
+pub(crate) fn rmdir(path: &str) -> Result<(), FileError> {
+    let cwd = cwd();
+    let mut header = crate::syscall::get_fs_header().lock();
+    let dir = header.lookup(cwd, path)?;
+    if dir == ROOT {
+        return Err(FileError::InvalidPath);
+    }
+    header.check_dir(dir)?;
+    if header.children(dir).next().is_some() {
+        return Err(FileError::DirectoryNotEmpty);
+    }
+    // Its entry gets reused once it's deleted, and the working directory would turn into whatever that is
+    if crate::process::is_anyones_cwd(dir) {
+        return Err(FileError::FileAlreadyOpen);
+    }
+    header.entries[dir].flags.set(FileFlags::DELETED, true);
+    mark_header_dirty();
+    Ok(())
+}
+
+/// Writes the entries of the directory at path to out (as many as fit), and returns how many entries it has in total.
+/// (. and .. aren't included, since every directory has them)
+pub(crate) fn readdir(path: &str, out: &mut [DirEntry]) -> Result<usize, FileError> {
+    let cwd = cwd();
+    let header = crate::syscall::get_fs_header().lock();
+    let dir = header.lookup(cwd, path)?;
+    header.check_dir(dir)?;
+
+    let mut count = 0;
+    for entry in header.children(dir) {
+        if let Some(slot) = out.get_mut(count) {
+            *slot = DirEntry {
+                name: entry.name,
+                is_dir: entry.is_dir(),
+                size: if entry.is_dir() { 0 } else { entry.len },
+            };
         }
-        let split: Vec<_> = file.path[root.len()..].split(|&b| b == b'/').collect();
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Makes the directory at path the current process' working directory, which relative paths start from
+pub(crate) fn chdir(path: &str) -> Result<(), FileError> {
+    let cwd = cwd();
+    let dir = {
+        let header = crate::syscall::get_fs_header().lock();
+        let dir = header.lookup(cwd, path)?;
+        header.check_dir(dir)?;
+        dir
+    };
+    crate::process::set_cwd(dir);
+    Ok(())
+}
+
+/// Writes the absolute path of the current process' working directory to out, and returns its length.
+/// Fails with PathTooLong if it doesn't fit, or FileNotFound if the directory was removed
+pub(crate) fn getcwd(out: &mut [u8]) -> Result<usize, FileError> {
+    let cwd = cwd();
+    let header = crate::syscall::get_fs_header().lock();
+    header.check_dir(cwd)?;
+
+    let mut names = Vec::new();
+    let mut curr = cwd;
+    while curr != ROOT {
+        names.push(header.entries[curr].name());
+        curr = header.entries[curr].parent;
+    }
+    let path = if names.is_empty() {
+        String::from("/")
+    } else {
+        names.iter().rev().fold(String::new(), |path, name| path + "/" + name)
+    };
+
+    let dst = out.get_mut(..path.len()).ok_or(FileError::PathTooLong)?;
+    dst.copy_from_slice(path.as_bytes());
+    Ok(path.len())
+}
 
-        // If there aren't any slashes in the path (after removing the root), it's a top level file
-        if split.len() == 1 {
-            files.push(file.clone());
+/// Brings a header from before FS_MAGIC up to date
+fn upgrade(header: &mut Header) {
+    upgrade_to_tree(header);
+    // Lengths used to be in whole sectors
+    for i in 0..header.first_null {
+        header.entries[i].len = header.entries[i].len.saturating_mul(512);
+    }
+    build_used_map(header);
+    header.magic = FS_MAGIC;
+    mark_header_dirty();
+}
+
+/// Fills in the free sector map of a header from before it existed, from the sectors its files take up.
+/// (Whatever was in the sector it's in until now is garbage)
+fn build_used_map(header: &mut Header) {
+    header.used = [0; DISK_SECTORS / 8];
+    header.set_used(0, HEADER_SECTORS, true);
+    for i in 0..header.first_null {
+        let entry = header.entries[i];
+        // Deleted files never gave their sectors back before, so this is where they do
+        if !entry.is_deleted() && !entry.is_dir() {
+            header.set_used(entry.sector, entry.sectors(), true);
+        }
+    }
+}
+
+/// Converts a header from before directories existed, where every entry's name was its whole path, into a tree.
+/// Every folder in those paths becomes a directory. (Names that are too long get cut short)
+/// Deleted entries are left alone, and never reused for the new directories: the ones we haven't gotten to yet still hold
+/// their old paths
+fn upgrade_to_tree(header: &mut Header) {
+    // The old paths are where the names and parents are now
+    let paths: Vec<String> = (0..header.first_null).map(|i| {
+        let raw: [u8; MAX_NAME_LENGTH + size_of::<Inode>()] =
+            unsafe { core::ptr::addr_of!(header.entries[i]).cast::<[u8; MAX_NAME_LENGTH + size_of::<Inode>()]>().read() };
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+        String::from_utf8_lossy(&raw[..len]).into_owned()
+    }).collect();
+    // Until an entry gets its real name, it shouldn't be found under its old one
+    for i in 0..paths.len() {
+        if !header.entries[i].is_deleted() {
+            header.entries[i].name = [0; MAX_NAME_LENGTH];
+            header.entries[i].parent = ROOT;
+        }
+    }
+
+    for (i, path) in paths.iter().enumerate() {
+        if header.entries[i].is_deleted() {
+            continue;
         }
-        else {
-            // Otherwise, get the folder
-            let name = unsafe { core::str::from_utf8_unchecked(split[0]) };
-            if folders.iter().all(|item| *item != name) {
-                folders.push(name.to_string())
+        let mut names = path.split('/').filter(|name| !name.is_empty()).map(truncate_name).peekable();
+        let mut dir = ROOT;
+        while let Some(name) = names.next() {
+            if names.peek().is_none() {
+                header.entries[i].set_name(name);
+                header.entries[i].parent = dir;
+            } else {
+                dir = match header.find(dir, name) {
+                    Some(existing) => existing,
+                    None => {
+                        let inode = header.next_unused().expect("No room to upgrade the file system");
+                        header.init_entry(inode, dir, name, FileFlags::DIRECTORY);
+                        inode
+                    }
+                };
             }
         }
     }
 }
 
+/// Must only be locked with interrupts disabled (as they are in syscalls). Otherwise a tick could switch to someone else while
+/// it's held, and if they're in a syscall that needs it, they'd spin forever with nothing to switch back to us
 pub(crate) static HEADER: Lazy<Mutex<&mut Header>> = Lazy::new(read_header);
@@ -5,14 +5,57 @@ pub(crate) struct MultibootInfo {
     pub flags: u32,
     pub mem_lower: usize,
     pub mem_upper: usize,
-    // there are a ton of other fields, but at least for now, we only need the top 3, so why bother?
-    _padding: [u8; 120-3*4] // 120 is the actual size of the struct, 3 fields * 4 bytes each.
+    pub boot_device: u32,
+    pub cmdline: u32,
+    pub mods_count: u32,
+    pub mods_addr: u32,
+    pub syms: [u32; 4],
+    pub mmap_length: u32,
+    pub mmap_addr: u32,
+    // there are a ton of other fields, but we only need the ones up to the memory map, so why bother?
+    _padding: [u8; 120-13*4] // 120 is the actual size of the struct, 13 fields * 4 bytes each.
+}
+
+/// An entry in the memory map GRUB gives us.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub(crate) struct MemoryMapEntry {
+    /// The size of the rest of the entry (not counting this field). Entries can be bigger than this struct.
+    pub size: u32,
+    pub addr: u64,
+    pub len: u64,
+    pub entry_type: u32,
+}
+
+/// The memory map entry type of RAM we are free to use. Every other type is reserved in some way.
+pub(crate) const MEMORY_AVAILABLE: u32 = 1;
+
+impl MultibootInfo {
+    /// Returns the memory map GRUB gave us, or None if it didn't give us one
+    pub fn memory_map(&self) -> Option<impl Iterator<Item = MemoryMapEntry>> {
+        if self.flags & MULTIBOOT_INFO_MEM_MAP == 0 {
+            return None;
+        }
+
+        let mut addr = self.mmap_addr as usize;
+        let end = addr + self.mmap_length as usize;
+        Some(core::iter::from_fn(move || {
+            if addr >= end {
+                return None;
+            }
+            let entry = unsafe { (addr as *const MemoryMapEntry).read_unaligned() };
+            addr += entry.size as usize + core::mem::size_of::<u32>(); // size doesn't include itself
+            Some(entry)
+        }))
+    }
 }
 
 /// GRUB puts this number into EAX. If its contents are different, something has gone really wrong
 const MAGIC_NUMBER: u32 = 0x2BADB002;
 /// Bitmask for finding out whether the low/high memory info in MultibootInfo is valid
 const MULTIBOOT_INFO_MEMORY: u32 = 0x00000001;
+/// Bitmask for finding out whether the memory map in MultibootInfo is valid
+const MULTIBOOT_INFO_MEM_MAP: u32 = 0x00000040;
 
 pub(crate) fn verify(magic: u32, flags: u32) -> Result<(), &'static str> {
     if magic != MAGIC_NUMBER {
@@ -1,54 +1,231 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem::size_of, ptr::null_mut};
+use spin::Mutex;
 use crate::paging::{*};
 
-#[derive(Clone, Copy)]
-pub(crate) struct ProcessHeapData {
-    counter: usize
+/// A node in the free list. It lives at the start of the free block it describes.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
 }
-impl ProcessHeapData {
-    pub(crate) fn new() -> Self {
-        Self { counter: 0 }
+
+/// Every block (free or allocated) is a multiple of this in size and alignment,
+/// which guarantees that whatever is left over after splitting a block can hold a FreeBlock.
+const BLOCK_ALIGN: usize = size_of::<FreeBlock>();
+
+/// A first-fit free list allocator. Blocks are kept sorted by address so neighbours can be merged back together.
+struct FreeList {
+    head: *mut FreeBlock,
+    /// The end of the mapped part of the heap. Everything after it (up until `end`) is reserved but not yet backed by frames
+    top: usize,
+    /// The end of the heap's reserved virtual memory
+    end: usize,
+    /// Whether the reserved memory is already an area of the process (see USER_HEAP_START), so growing doesn't have to map anything
+    lazy: bool,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    const fn empty() -> Self {
+        Self { head: null_mut(), top: 0, end: 0, lazy: false }
+    }
+
+    const fn lazy(start: usize, end: usize) -> Self {
+        Self { head: null_mut(), top: start, end, lazy: true }
+    }
+
+    /// Rounds the layout up so that the resulting block can later be returned to the list
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = usize::max(layout.size(), BLOCK_ALIGN).next_multiple_of(BLOCK_ALIGN);
+        let align = usize::max(layout.align(), BLOCK_ALIGN);
+        (size, align)
+    }
+
+    /// Returns the address of a free block that can fit size with the given alignment, or null if there isn't one
+    unsafe fn take(&mut self, size: usize, align: usize) -> *mut u8 {
+        let mut prev: *mut *mut FreeBlock = &mut self.head;
+        while !(*prev).is_null() {
+            let block = *prev;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+
+            let start = block_start.next_multiple_of(align);
+            let end = start + size;
+            if end > block_end {
+                prev = &mut (*block).next;
+                continue;
+            }
+
+            // Found one. Remove it from the list and return whatever we don't need on either side of it.
+            // Since everything is a multiple of BLOCK_ALIGN, the leftovers are always either empty or big enough for a FreeBlock.
+            let next = (*block).next;
+            *prev = next;
+            if end < block_end {
+                let back = end as *mut FreeBlock;
+                back.write(FreeBlock { size: block_end - end, next });
+                *prev = back;
+            }
+            if start > block_start {
+                // The block is still at the same address, so just shrink it and put it back
+                (*block).size = start - block_start;
+                (*block).next = *prev;
+                *prev = block;
+            }
+            return start as *mut u8;
+        }
+        null_mut()
+    }
+
+    /// Returns addr..addr+size to the list, merging it with its neighbours where possible
+    unsafe fn give(&mut self, addr: usize, size: usize) {
+        // Find the last block before addr
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut next: *mut FreeBlock = self.head;
+        while !next.is_null() && (next as usize) < addr {
+            prev = next;
+            next = (*next).next;
+        }
+
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next });
+
+        // Merge with the following block
+        if !next.is_null() && addr + size == next as usize {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+        }
+
+        // Merge with the preceding block
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    /// Maps (up to) size more bytes at the top of the heap, and adds them to the list.
+    /// Returns false if the heap can't grow any more.
+    unsafe fn grow(&mut self, size: usize) -> bool {
+        if self.top >= self.end {
+            return false;
+        }
+        let end = usize::min(self.top + size.next_multiple_of(PAGE_SIZE), self.end);
+        if self.lazy {
+            // Pages get backed once they're touched
+            self.give(self.top, end - self.top);
+            self.top = end;
+            return true;
+        }
+
+        // The heap is identity mapped, since plenty of code (e.g. PageDirectory::switch_to) relies on heap addresses being physical ones.
+        // So every page gets the frame at the same address. Frames that are already used (reserved by the memory map, or taken by get_free_frame on small machines) are simply skipped.
+        // The heap's page tables are shared by every directory, so it doesn't matter which one is active
+        let dir: &mut PageDirectory = PageDirectory::curr().as_mut().unwrap();
+        for page in (self.top..end).step_by(PAGE_SIZE) {
+            if FRAMES_USAGE.lock().is_frame_used(page / PAGE_SIZE) {
+                continue;
+            }
+            dir.make_page(page, page, PageFlags::RW).unwrap();
+            self.give(page, PAGE_SIZE);
+        }
+
+        self.top = end;
+        true
+    }
+
+    /// Returns a block that fits layout, growing the heap if needed. Returns null if it can't grow any more
+    unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+        loop {
+            let ptr = self.take(size, align);
+            if !ptr.is_null() {
+                return ptr;
+            }
+
+            // Nothing fits, so grow the heap. We might need to skip up to align bytes to align the new block
+            if !self.grow(size + align) {
+                return null_mut();
+            }
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        if ptr.is_null() {
+            return;
+        }
+        let (size, _) = Self::block_layout(layout);
+        self.give(ptr as usize, size);
     }
 }
 
+/// Where user programs' heaps live. Every process gets this range as an area (see execution::load_image),
+/// so pages are only allocated once they're touched, and the heap doesn't need to map anything to grow.
+/// User programs can't see the kernel's heap at all
+pub(crate) const USER_HEAP_START: usize = 0x8000_0000;
+pub(crate) const USER_HEAP_END: usize = 0xB000_0000;
+
+static FREE_LIST: Mutex<FreeList> = Mutex::new(FreeList::empty());
+/// The heap of the user program (each program has its own copy of it, like the rest of its memory)
+static USER_FREE_LIST: Mutex<FreeList> = Mutex::new(FreeList::lazy(USER_HEAP_START, USER_HEAP_END));
+static mut HAS_INIT: bool = false;
+
 pub struct Heap;
 
 impl Heap {
     pub(crate) unsafe fn alloc_internal(&self, layout: Layout) -> *mut u8 {
-        let whole_pages: usize = layout.size() / PAGE_SIZE;
-        let part_page: usize = layout.size() % PAGE_SIZE;
-
-        let proc: crate::process::Process = crate::process::get_curr_process();
-        let dir: &mut crate::paging::PageDirectory = proc.ctx.as_ref().dir.as_mut().unwrap(); // can't be null if the process exists
-
-        let usage = FRAMES_USAGE.lock();
-        
-        for i in 0..whole_pages {
-            let free: usize = usage.get_free_frame();
-            let page: &Page = dir.make_page(proc.data.counter, PageFlags::RW | PageFlags::USER, false).unwrap();
+        if !has_init() {
+            return null_mut();
         }
 
-        core::ptr::null_mut()
+        FREE_LIST.lock().alloc(layout)
     }
 
-    pub(crate) unsafe fn dealloc_internal(&self, layout: Layout) {
-
+    pub(crate) unsafe fn dealloc_internal(&self, ptr: *mut u8, layout: Layout) {
+        FREE_LIST.lock().dealloc(ptr, layout);
     }
 }
 
 unsafe impl GlobalAlloc for Heap {
+    // The kernel's heap goes through a syscall, so that interrupts are disabled while it's locked.
+    // User programs use their own heap instead, since the kernel's isn't mapped for them (and Alloc is kernel only)
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut out = core::ptr::null_mut();
-        crate::syscall::Alloc::call(&mut out, layout);
-        out
+        if crate::userspace::in_user_mode() {
+            USER_FREE_LIST.lock().alloc(layout)
+        } else {
+            crate::syscall::Alloc::call(layout)
+        }
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        crate::syscall::Dealloc::call(ptr, layout)
+        if crate::userspace::in_user_mode() {
+            USER_FREE_LIST.lock().dealloc(ptr, layout)
+        } else {
+            crate::syscall::Dealloc::call(ptr, layout)
+        }
     }
 }
 
+#[global_allocator]
+pub(crate) static HEAP: Heap = Heap;
 
+/// Reserves space_start..space_start+size for the heap.
+/// Pages are only mapped (and their frames used) once the heap actually grows into them.
 pub(crate) unsafe fn init(space_start: usize, size: usize) {
-    
+    // Every directory shares the heap's page tables, so the heap has to start and end on a table boundary
+    const TABLE_SPAN: usize = PAGE_SIZE * PAGE_ENTRIES;
+    let start = space_start.next_multiple_of(TABLE_SPAN);
+    let end = (space_start + size) / TABLE_SPAN * TABLE_SPAN;
+    share_tables(start, end - start);
+
+    let mut list = FREE_LIST.lock();
+    list.top = start;
+    list.end = end;
+    HAS_INIT = true;
 }
+
+#[inline]
+pub fn has_init() -> bool { unsafe { HAS_INIT } }
@@ -1,5 +1,6 @@
 use core::arch::asm;
 use core::ops::Deref;
+use bitflags::bitflags;
 use spin::Lazy;
 
 #[derive(Copy, Clone)]
@@ -38,6 +39,50 @@ pub fn init() {
     }
 }
 
+/// What the CPU pushes onto the stack when an interrupt occurs.
+/// (If the interrupt came from user mode, it also pushes the user's ESP and SS afterwards)
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct InterruptFrame {
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+}
+
+/// The whole state of the interrupted code, as saved by our own entry stubs (see syscall.rs).
+/// Restoring it and doing an iretd resumes the code exactly where it was interrupted.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TrapFrame {
+    pub gs: u32,
+    pub fs: u32,
+    pub es: u32,
+    pub ds: u32,
+    // Pushed by pushad
+    pub edi: u32,
+    pub esi: u32,
+    pub ebp: u32,
+    /// The kernel's esp at the time of pushad. Ignored by popad
+    pub kernel_esp: u32,
+    pub ebx: u32,
+    pub edx: u32,
+    pub ecx: u32,
+    pub eax: u32,
+    // Pushed by the CPU
+    pub eip: u32,
+    pub cs: u32,
+    pub eflags: u32,
+    /// Only valid if the interrupt came from user mode (see from_user)
+    pub user_esp: u32,
+    /// Only valid if the interrupt came from user mode (see from_user)
+    pub user_ss: u32,
+}
+
+impl TrapFrame {
+    /// Whether the interrupted code was running in ring 3
+    pub fn from_user(&self) -> bool { self.cs & 3 == 3 }
+}
+
 type ISR = extern "x86-interrupt" fn();
 type ISRErr = extern "x86-interrupt" fn(u32);
 
@@ -60,6 +105,17 @@ impl Handler {
         Handler::new_raw(isr as *const () as u32, gate_type, dpl)
     }
 
+    /// A task gate, which switches to the task whose TSS selector is given
+    pub fn task(selector: u16) -> Handler {
+        Handler {
+            ptr_low: 0,
+            ptr_high: 0,
+            selector,
+            attributes: 0x80 | GateType::Task as u8,
+            _reserved: 0,
+        }
+    }
+
     pub const fn null() -> Handler {
         Handler {
             ptr_low: 0,
@@ -106,55 +162,96 @@ pub(crate) fn disable() {
 
 pub(crate) fn is_enabled() -> bool { unsafe { ENABLED } }
 
+/// EFLAGS' interrupt flag
+pub(crate) const EFLAGS_IF: u32 = 0x200;
+
+/// Turns interrupts on or off for whoever made the current syscall, once it returns to them. Syscalls themselves always
+/// run with interrupts disabled, and return with the caller's EFLAGS, so sti or cli in here wouldn't outlast the syscall
+pub(crate) fn set_for_caller(enabled: bool) {
+    let frame = crate::syscall::curr_frame();
+    unsafe {
+        if enabled {
+            (*frame).eflags |= EFLAGS_IF;
+        } else {
+            (*frame).eflags &= !EFLAGS_IF;
+        }
+        ENABLED = enabled;
+    }
+}
+
+/// Whether IF is set right now. (Unlike is_enabled, this is the CPU's own state)
+#[inline(always)]
+pub fn if_set() -> bool {
+    let eflags: u32;
+    unsafe { asm!("pushfd", "pop {}", out(reg) eflags) }
+    eflags & EFLAGS_IF != 0
+}
+
+/// An exception the code that was running caused. If it's a user program, that's its own problem, so only it has to die.
+/// If it's the kernel, there's nothing to do but panic
+fn exception(name: &str, frame: &InterruptFrame, err: Option<u32>) -> ! {
+    if frame.cs & 3 == 3 {
+        // (We write to the console directly since printing normally would re-enable interrupts)
+        use core::fmt::Write;
+        let _ = writeln!(
+            crate::vga_console::CONSOLE.lock(),
+            "Exception: {} (eip={:#X}). Killing the process.", name, frame.eip
+        );
+        crate::process::kill_process();
+    }
+    match err {
+        Some(err) => panic!("EXCEPTION: {}\n{:?}, ERROR CODE: {:#X}", name, frame, err),
+        None => panic!("EXCEPTION: {}\n{:?}", name, frame),
+    }
+}
+
 macro_rules! int_fn {
     ($name:tt) => {
-        extern "x86-interrupt" fn $name() {
-            panic!(concat!("EXCEPTION: ", stringify!($name)));
+        extern "x86-interrupt" fn $name(frame: InterruptFrame) {
+            exception(stringify!($name), &frame, None);
         }
     };
 }
 
 macro_rules! int_fn_err {
     ($name:tt) => {
-        extern "x86-interrupt" fn $name(err: u32) {
-            panic!(
-                concat!("EXCEPTION: ", stringify!($name), ", ERROR CODE: {:#X}"),
-                err
-            );
+        extern "x86-interrupt" fn $name(frame: InterruptFrame, err: u32) {
+            exception(stringify!($name), &frame, Some(err));
         }
     };
 }
 
-macro_rules! int_set {
-    ($idx:literal, $name:tt) => {
-        IDT[$idx] = Handler::new($name, GateType::DInterrupt, 0);
+/// For exceptions that aren't caused by whatever was running (e.g. hardware failures), so there's no one to blame
+macro_rules! int_fn_fatal {
+    ($name:tt) => {
+        extern "x86-interrupt" fn $name(frame: InterruptFrame) {
+            panic!(concat!("EXCEPTION: ", stringify!($name), "\n{:?}"), frame);
+        }
     };
 }
 
-macro_rules! int_set_err {
+macro_rules! int_set {
     ($idx:literal, $name:tt) => {
-        IDT[$idx] = Handler::new_err($name, GateType::DInterrupt, 0);
+        IDT[$idx] = Handler::new_raw($name as u32, GateType::DInterrupt, 0);
     };
 }
 
 // https://wiki.osdev.org/Exceptions
 int_fn!(divide_by_zero);
 int_fn!(debug);
-int_fn!(non_maskable_interrupt);
+int_fn_fatal!(non_maskable_interrupt);
 int_fn!(breakpoint);
 int_fn!(overflow);
 int_fn!(bound_range_exceeded);
 int_fn!(invalid_opcode);
 int_fn!(device_not_available);
-int_fn_err!(double_fault);
 int_fn_err!(invalid_tss);
 int_fn_err!(segment_not_present);
 int_fn_err!(stack_segment_fault);
 int_fn_err!(general_protection_fault);
-int_fn_err!(page_fault);
 int_fn!(x87_floating_point_exception);
 int_fn_err!(alignment_check);
-int_fn!(machine_check);
+int_fn_fatal!(machine_check);
 int_fn!(simd_floating_point_exception);
 int_fn!(virtualization_exception);
 int_fn_err!(control_protection_exception);
@@ -162,6 +259,91 @@ int_fn!(hypvervisor_injection_exception);
 int_fn_err!(vmm_communication_exception);
 int_fn_err!(security_exception);
 
+bitflags! {
+    /// The error code the CPU pushes on a page fault
+    #[derive(Clone, Copy)]
+    struct PageFaultError: u32 {
+        /// Set if the page was present (so this is a protection violation), unset if it wasn't mapped at all
+        const PRESENT = 1;
+        /// Set if the access was a write, unset if it was a read
+        const WRITE = 2;
+        /// Set if the access happened in ring 3
+        const USER = 4;
+        /// Set if a reserved bit was set in one of the paging structures
+        const RESERVED = 8;
+        /// Set if the access was an instruction fetch
+        const INSTRUCTION_FETCH = 16;
+    }
+}
+
+impl core::fmt::Display for PageFaultError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let access = if self.contains(Self::INSTRUCTION_FETCH) { "executing" }
+            else if self.contains(Self::WRITE) { "writing" }
+            else { "reading" };
+        let cause = if self.contains(Self::RESERVED) { "a reserved bit" }
+            else if self.contains(Self::PRESENT) { "a protected page" }
+            else { "a non-present page" };
+        let mode = if self.contains(Self::USER) { "user" } else { "kernel" };
+        write!(f, "{} {} in {} mode", access, cause, mode)
+    }
+}
+
+/// The address whose access caused the last page fault
+fn cr2() -> usize {
+    let addr: usize;
+    unsafe { asm!("mov {}, cr2", out(reg) addr) }
+    addr
+}
+
+extern "x86-interrupt" fn page_fault(frame: InterruptFrame, err: u32) {
+    let addr = cr2();
+    let err = PageFaultError::from_bits_truncate(err);
+
+    // Most pages of a process are only allocated once they're first touched
+    if !err.contains(PageFaultError::PRESENT) && crate::vma::handle_fault(addr) {
+        return;
+    }
+    // Forked processes share their pages until one of them writes to one
+    if err.contains(PageFaultError::PRESENT | PageFaultError::WRITE) && unsafe { crate::paging::handle_cow_fault(addr) } {
+        return;
+    }
+
+    if err.contains(PageFaultError::USER) {
+        // A user program did something it shouldn't have. That's its own problem, so only it has to die.
+        // (We write to the console directly since printing normally would re-enable interrupts)
+        use core::fmt::Write;
+        let _ = writeln!(
+            crate::vga_console::CONSOLE.lock(),
+            "Segmentation fault: {} at {:#X} (eip={:#X}). Killing the process.", err, addr, frame.eip
+        );
+        crate::process::kill_process();
+    }
+
+    panic!(
+        "EXCEPTION: page_fault while {} at {:#X}\neip={:#X} cs={:#X} eflags={:#X}, ERROR CODE: {:#X}",
+        err, addr, frame.eip, frame.cs, frame.eflags, err.bits()
+    );
+}
+
+/// The double fault task (see userspace::write_double_fault_tss). The CPU switches to it on a double fault, with a stack
+/// of its own, since the likeliest cause is that the faulting code ran out of stack (i.e. into its guard page, see
+/// process::KernelStack), so there was nowhere to push the page fault's frame. Nothing can go on after that, so we just
+/// say what happened and stop
+pub(crate) extern "C" fn double_fault_task() -> ! {
+    let (eip, esp) = crate::userspace::faulted_task_state();
+    use core::fmt::Write;
+    unsafe {
+        // Whoever held the console isn't coming back, and printing normally would re-enable interrupts
+        crate::vga_console::CONSOLE.force_unlock();
+    }
+    let _ = writeln!(
+        crate::vga_console::CONSOLE.lock(),
+        "EXCEPTION: double_fault (most likely a kernel stack overflow)\neip={:#X} esp={:#X}", eip, esp
+    );
+    loop { unsafe { asm!("cli", "hlt"); } }
+}
+
 unsafe fn setup_idt() {
     int_set!(0x0, divide_by_zero);
     int_set!(0x1, debug);
@@ -171,19 +353,19 @@ unsafe fn setup_idt() {
     int_set!(0x5, bound_range_exceeded);
     int_set!(0x6, invalid_opcode);
     int_set!(0x7, device_not_available);
-    int_set_err!(0x8, double_fault);
-    int_set_err!(0xA, invalid_tss);
-    int_set_err!(0xB, segment_not_present);
-    int_set_err!(0xC, stack_segment_fault);
-    int_set_err!(0xD, general_protection_fault);
-    int_set_err!(0xE, page_fault);
+    IDT[0x8] = Handler::task(crate::userspace::double_fault_tss_selector());
+    int_set!(0xA, invalid_tss);
+    int_set!(0xB, segment_not_present);
+    int_set!(0xC, stack_segment_fault);
+    int_set!(0xD, general_protection_fault);
+    IDT[0xE] = Handler::new_raw(page_fault as u32, GateType::DInterrupt, 0);
     int_set!(0x10, x87_floating_point_exception);
-    int_set_err!(0x11, alignment_check);
+    int_set!(0x11, alignment_check);
     int_set!(0x12, machine_check);
     int_set!(0x13, simd_floating_point_exception);
     int_set!(0x14, virtualization_exception);
-    int_set_err!(0x15, control_protection_exception);
+    int_set!(0x15, control_protection_exception);
     int_set!(0x1C, hypvervisor_injection_exception);
-    int_set_err!(0x1D, vmm_communication_exception);
-    int_set_err!(0x1E, security_exception);
+    int_set!(0x1D, vmm_communication_exception);
+    int_set!(0x1E, security_exception);
 }
@@ -2,7 +2,8 @@
 
 use crate::events::{Event, EventHandler};
 use crate::interrupts::GateType;
-use crate::{interrupts, io, pic};
+use crate::{interrupts, io, pic, process::WaitQueue};
+use alloc::collections::VecDeque;
 use spin::Mutex;
 
 /// Key down map for scancode set 1
@@ -262,6 +263,50 @@ pub(crate) fn init() {
             *caps = !*caps;
         }
     });
+
+    // Whatever is typed can be read from the keyboard's descriptor (see read_input)
+    ON_KEY_DOWN.lock().subscribe(|args| {
+        let shift = is_key_pressed(Key::LShift) || is_key_pressed(Key::RShift);
+        let c = if args.0 == Key::Backspace {
+            Some('\x08')
+        } else if shift {
+            args.0.to_shifted_char()
+        } else {
+            args.0.to_char()
+        };
+        let Some(mut c) = c else { return; };
+        // We're in the interrupt handler already, so there's no need for is_caps_lock_active to mask it
+        if *CAPS_LOCK.lock() && c.is_ascii_alphabetic() {
+            c = if shift { c.to_ascii_lowercase() } else { c.to_ascii_uppercase() };
+        }
+
+        let mut input = INPUT.lock();
+        // Nobody is reading, so there's no point in keeping more
+        if input.len() < MAX_INPUT {
+            input.push_back(c as u8);
+        }
+        drop(input);
+        INPUT_READY.wake_all();
+    });
+}
+
+/// Reads up to buffer.len() typed characters, blocking until there's at least one. Must be called with interrupts disabled
+pub(crate) fn read_input(buffer: &mut [u8]) -> usize {
+    if buffer.is_empty() {
+        return 0;
+    }
+    loop {
+        let mut input = INPUT.lock();
+        if !input.is_empty() {
+            let count = usize::min(buffer.len(), input.len());
+            for (dst, src) in buffer.iter_mut().zip(input.drain(..count)) {
+                *dst = src;
+            }
+            return count;
+        }
+        drop(input);
+        INPUT_READY.wait();
+    }
 }
 
 fn get_state(scancode: u8) -> (u8, bool) {
@@ -374,3 +419,10 @@ pub(crate) static ON_KEY_DOWN: Mutex<Event<KeyArgs>> = Mutex::new(Event::<KeyArg
 pub(crate) static ON_KEY_UP: Mutex<Event<KeyArgs>> = Mutex::new(Event::<KeyArgs>::new());
 
 static CAPS_LOCK: Mutex<bool> = Mutex::new(false);
+
+/// How many typed characters are kept until someone reads them
+const MAX_INPUT: usize = 256;
+/// Characters that were typed but not read yet
+static INPUT: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+/// Processes waiting for something to be typed
+static INPUT_READY: WaitQueue = WaitQueue::new();
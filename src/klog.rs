@@ -0,0 +1,37 @@
+use alloc::collections::VecDeque;
+use core::fmt;
+use spin::Mutex;
+
+/// How many bytes of the log are kept. Once it's full, the oldest ones make room for new ones
+pub const LOG_SIZE: usize = 16384;
+
+/// Messages from the kernel that don't belong on the screen (e.g. syscall traces), until someone reads them
+static LOG: Mutex<VecDeque<u8>> = Mutex::new(VecDeque::new());
+
+struct LogWriter<'a>(&'a mut VecDeque<u8>);
+
+impl fmt::Write for LogWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Only the end of whatever doesn't fit is kept
+        let bytes = &s.as_bytes()[s.len().saturating_sub(LOG_SIZE)..];
+        let overflow = (self.0.len() + bytes.len()).saturating_sub(LOG_SIZE);
+        self.0.drain(..overflow);
+        self.0.extend(bytes);
+        Ok(())
+    }
+}
+
+/// Appends args to the log. Must be called with interrupts disabled
+pub(crate) fn log(args: fmt::Arguments) {
+    let _ = fmt::Write::write_fmt(&mut LogWriter(&mut LOG.lock()), args);
+}
+
+/// Takes up to buffer.len() bytes out of the log (the oldest ones first), and returns how many there were
+pub(crate) fn read(buffer: &mut [u8]) -> usize {
+    let mut log = LOG.lock();
+    let count = usize::min(buffer.len(), log.len());
+    for (dst, src) in buffer.iter_mut().zip(log.drain(..count)) {
+        *dst = src;
+    }
+    count
+}
@@ -5,6 +5,7 @@
 #![feature(asm_const)]
 #![feature(naked_functions)]
 #![feature(new_uninit)]
+#![feature(allocator_api)]
 #![no_std]
 #![no_main]
 
@@ -31,6 +32,14 @@ pub mod paging;
 mod userspace;
 pub mod syscall;
 pub mod process;
+pub mod vma;
+pub mod fd;
+pub mod errno;
+pub mod pipe;
+mod uaccess;
+pub mod klog;
+#[cfg(feature = "selftest")]
+mod selftest;
 
 extern "C" {
     static CODE_SEG: usize;
@@ -50,13 +59,12 @@ pub(crate) extern "C" fn kernel_main(info: &grub::MultibootInfo, magic: u32) ->
     userspace::init();
     syscall::init();
 
-    let heap_start_addr = paging::init();
+    let heap_start_addr = paging::init(info);
 
     unsafe {
-        // according to GRUB, there are info.mem_upper free KBs of memory at address 0x100_000.
-        // we're using a maximum of 50MB to get faster loading times,
-        // and only start at heap_start_addr since some of the heap was used by paging.
-        heap::init(heap_start_addr, core::cmp::min(50 * 1024 * 1024, info.mem_upper * 1024));
+        // The heap only reserves virtual memory (frames are taken as it grows), so it can take up
+        // everything up until paging::HEAP_LIMIT. We start at heap_start_addr since some of it was used by paging.
+        heap::init(heap_start_addr, paging::HEAP_LIMIT - heap_start_addr);
     }
 
 
@@ -64,9 +72,16 @@ pub(crate) extern "C" fn kernel_main(info: &grub::MultibootInfo, magic: u32) ->
     keyboard::init();
     ata::init();
 
-    execution::execute_file(&mut fs::File::open("/shell").unwrap());
+    // Starts running along with the shell
+    process::spawn_kernel_thread(fs::flush_thread, fd::FdTable::default());
 
-    loop {}
+    // The self tests start the shell themselves once they're done, so that it doesn't get in their way
+    #[cfg(feature = "selftest")]
+    process::spawn_kernel_thread(selftest::run, fd::FdTable::standard());
+    #[cfg(not(feature = "selftest"))]
+    execution::execute_file(&fs::File::open("/shell").unwrap()).expect("/shell isn't a valid executable");
+
+    process::start();
 }
 
 #[panic_handler]
@@ -1,4 +1,4 @@
-use alloc::{boxed::Box};
+use alloc::{boxed::Box, collections::BTreeMap};
 use bitfield::bitfield;
 use bitflags::bitflags;
 use core::{mem::{size_of, transmute}, arch::asm};
@@ -16,6 +16,8 @@ bitfield! {
     pub accessed, set_accessed: 3;
     pub dirty, set_dirty: 4;
     pub unused, _: 5, 11;
+    /// One of the bits the CPU leaves for us. Set on read only pages that should be copied once they're written to (see fork)
+    pub cow, set_cow: 9;
     _frame_underlying, _: 12, 31;
 }
 
@@ -30,6 +32,8 @@ bitflags! {
         const PCD = 16;
         const ACCESSED = 32;
         const DIRTY = 64;
+        /// Not a hardware flag: see Page::cow
+        const COW = 0x200;
     }
 }
 
@@ -46,8 +50,23 @@ impl Page {
 extern "C" {
     static KERNEL_LOAD_ADDR: usize;
     static KERNEL_END_ADDR: usize; // we can safely allocate memory immediately after the end of the kernel
+    // The code user programs start in (see map_kernel)
+    static USER_TEXT_START: usize;
+    static USER_TEXT_END: usize;
 }
 
+/// The page directory every process' directory is based on
+pub(crate) fn kernel_dir() -> *mut PageDirectory { unsafe { KERNEL_DIR } }
+
+/// The first address of the kernel image
+pub(crate) fn kernel_start() -> usize { unsafe { &KERNEL_LOAD_ADDR as *const _ as usize } }
+/// The first address after the end of the kernel image
+pub(crate) fn kernel_end() -> usize { unsafe { &KERNEL_END_ADDR as *const _ as usize } }
+
+/// The (identity mapped) kernel heap lives below this address, and user programs above it.
+/// Frames below it are left for the heap, get_free_frame only takes them once there are no others.
+pub const HEAP_LIMIT: usize = 0x4_000_000;
+
 const HEAP_START: usize = 0x100_000;
 static mut PLACEMENT_ADDR: usize = HEAP_START;
 /// The end of the paging heap. Calculated after init()
@@ -71,6 +90,11 @@ unsafe fn kmalloc(size: usize, align: bool) -> *mut u8 {
 }
 
 static mut CURR_DIR: *mut PageDirectory = core::ptr::null_mut();
+/// The page directory created by init(). Never freed.
+static mut KERNEL_DIR: *mut PageDirectory = core::ptr::null_mut();
+/// The indices of the page tables that every directory shares with the kernel's (see share_tables)
+static mut SHARED_TABLES: core::ops::Range<usize> = 0..0;
+
 #[repr(align(0x1000))] // 0x1000=PAGE_SIZE. Rust does not support constants in attributes.
 pub struct PageTable {
     pages: [Page; PAGE_ENTRIES],
@@ -82,18 +106,20 @@ pub struct PageDirectory {
 }
 
 impl PageDirectory {
-    /// Returns a new page directory, with the kernel mapped. Must be manually freed, via Box::from_raw
+    /// Returns a new page directory, with the kernel mapped. Must be manually freed, via Box::from_raw.
+    /// User programs can't see any of the kernel's memory (other than .user_text), or its page tables
     pub fn new() -> *mut Self {
         unsafe {
             let dir: *mut Self = Box::into_raw(Box::new_zeroed().assume_init());
-            (*dir).map_kernel(PageFlags::RW | PageFlags::USER);
-            (*dir).map_self(PageFlags::RW | PageFlags::USER);
-            (*dir).map_recursive(PageFlags::RW | PageFlags::USER);
+            (*dir).map_kernel(PageFlags::RW);
+            (*dir).map_shared();
+            (*dir).map_recursive(PageFlags::RW);
             dir
         }
     }
 
-    /// Identity maps the kernel addresses
+    /// Identity maps the kernel addresses. The code in .user_text is what user programs and threads start in
+    /// (see execution::start_frame), so it's mapped as read only user memory instead
     pub fn map_kernel(&mut self, flags: PageFlags) {
         unsafe {
             // We need to identity map the first megabyte (the physical address should equal the virtual address)
@@ -105,22 +131,24 @@ impl PageDirectory {
             // Map the kernel addresses. They also need to be identity mapped since 
             // after paging is enabled, the IP stays the same, so it should point at the same code.
             let unaligned_size: usize = (&KERNEL_END_ADDR as *const _ as usize) - (&KERNEL_LOAD_ADDR as *const _ as usize);
-            self.identity_map(
-                &KERNEL_LOAD_ADDR as *const _ as usize, // the linker puts extern's values in their memory addresses.
-                (unaligned_size + 0xFFF) & !0xFFF, // align the size (round up)
-                flags
-            );
+            let start = &KERNEL_LOAD_ADDR as *const _ as usize; // the linker puts extern's values in their memory addresses.
+            let end = start + ((unaligned_size + 0xFFF) & !0xFFF); // align the size (round up)
+            // (The linker aligns .user_text to pages)
+            let user_start = &USER_TEXT_START as *const _ as usize;
+            let user_end = &USER_TEXT_END as *const _ as usize;
+            self.identity_map(start, user_start - start, flags);
+            self.identity_map(user_start, user_end - user_start, PageFlags::USER);
+            self.identity_map(user_end, end - user_end, flags);
         }
     }
 
-    fn map_self(&mut self, flags: PageFlags) {
+    /// Points this directory at the kernel's shared page tables (see share_tables)
+    fn map_shared(&mut self) {
         unsafe {
-            // map PageDirectory
-            self.identity_map(
-                self as *const _ as usize,
-                size_of::<PageDirectory>(),
-                flags
-            );
+            let kernel: &PageDirectory = KERNEL_DIR.as_ref().unwrap();
+            for i in SHARED_TABLES.clone() {
+                self.page_tables[i] = kernel.page_tables[i];
+            }
         }
     }
 
@@ -149,7 +177,7 @@ impl PageDirectory {
         } else if core::ptr::eq(self, Self::curr()) {
             0xFFFFF000 as *mut PageDirectory
         } else {
-            0xFFBFE000 as *mut PageDirectory
+            0xFFBFF000 as *mut PageDirectory
         }
     }
 
@@ -200,7 +228,7 @@ impl PageDirectory {
 
     /// Allocates a new page table at the specified index and returns its virtual address
     /// If self != Self::curr(), Self::curr()'s 1022nd page table must be set to self.
-    unsafe fn make_table(&mut self, usage: &mut FramesUsage, index: usize, flags: PageFlags) -> *mut PageTable {
+    unsafe fn make_table(&mut self, usage: &mut FramesUsage, index: usize) -> *mut PageTable {
         // This function is called from within alloc::alloc. So we can't use that obviously
         let new_phys: u32 = if crate::heap::has_init() {
             // Thankfully a PageTable is precisely 4KB, so it fits perfectly inside of a frame. 
@@ -214,7 +242,9 @@ impl PageDirectory {
             kmalloc(PAGE_SIZE, true) as u32
         };
 
-        (*self.get_dir_ptr()).page_tables[index] = new_phys | PageFlags::PRESENT.bits() | flags.bits();
+        // The CPU only allows what both the table and the page allow. Tables can hold both kernel and user pages
+        // (e.g. the kernel image and .user_text), so they allow everything, and it's up to each page
+        (*self.get_dir_ptr()).page_tables[index] = new_phys | (PageFlags::PRESENT | PageFlags::RW | PageFlags::USER).bits();
 
         let virt: *mut PageTable = self.get_table_ptr(index);
 
@@ -233,45 +263,138 @@ impl PageDirectory {
     pub unsafe fn make_page(&mut self, virt_addr: usize, phys_addr: usize, flags: PageFlags) -> Result<&mut Page, ()> {
         let mut usage = FRAMES_USAGE.lock();
 
-        let in_kernel: bool = !Self::curr().is_null() && !core::ptr::eq(self, Self::curr());
-        if in_kernel {
-            // If we're modifying a different page directory from the active one,
-            // then the page table that we're modifying won't be mapped. So we won't be able to write to it.
-            // To combat this problem, we use a similar trick to map_recursive:
-            let kernel: &mut PageDirectory = Self::curr().as_mut().unwrap();
-            kernel.page_tables[1022] = (self as *const _ as u32) | (PageFlags::PRESENT | PageFlags::RW).bits();
-            Self::invalidate_tlb(0xFF800000);
-            Self::invalidate_tlb(0xFFBFE000);
-            // We can now access the page table by 0xFF800000 + table_index * PAGE_SIZE.
-        }
+        let in_kernel: bool = self.map_foreign();
 
         let (index, table_idx, curr_table) = self.find_table(virt_addr);
         let table: *mut PageTable = // Allocate the table if it doesn't already exist
-            if curr_table.is_null() { self.make_table(&mut usage, table_idx, flags) } 
+            if curr_table.is_null() { self.make_table(&mut usage, table_idx) } 
             else { curr_table };
 
         // Assign the page (it already exists as it's been either zero initialised or used then freed)
         let page: &mut Page = &mut (*table).pages[index];
-        if page.present() { return Err(()); }
+        if page.present() {
+            if in_kernel { Self::unmap_foreign(); }
+            return Err(());
+        }
 
         *page = Page(0); // Reset its state in case it's been freed
         page.set_user(flags.contains(PageFlags::USER));
         page.set_rw(flags.contains(PageFlags::RW));
+        page.set_cow(flags.contains(PageFlags::COW));
         usage.set_page_frame(page, phys_addr / PAGE_SIZE);
 
         Self::invalidate_tlb(virt_addr); 
 
         if in_kernel {
             // We're done modifying, unmap it now
-            let kernel: &mut PageDirectory = Self::curr().as_mut().unwrap();
-            kernel.page_tables[1022] = 0;
-            Self::invalidate_tlb(0xFF800000);
-            Self::invalidate_tlb(0xFFBFE000);
+            Self::unmap_foreign();
         }
 
         Ok(page)
     }
 
+    /// If we're modifying a different page directory from the active one,
+    /// then the page table that we're modifying won't be mapped. So we won't be able to write to it.
+    /// To combat this problem, we use a similar trick to map_recursive, and map self into the active directory's index 1022.
+    /// Returns whether that was necessary (in which case unmap_foreign must be called when done)
+    unsafe fn map_foreign(&mut self) -> bool {
+        if Self::curr().is_null() || core::ptr::eq(self, Self::curr()) {
+            return false;
+        }
+        let kernel: &mut PageDirectory = Self::curr().as_mut().unwrap();
+        kernel.page_tables[1022] = (self as *const _ as u32) | (PageFlags::PRESENT | PageFlags::RW).bits();
+        Self::invalidate_tlb(0xFF800000);
+        Self::invalidate_tlb(0xFFBFF000);
+        // We can now access the page table by 0xFF800000 + table_index * PAGE_SIZE.
+        true
+    }
+
+    /// Undoes map_foreign
+    unsafe fn unmap_foreign() {
+        let kernel: &mut PageDirectory = Self::curr().as_mut().unwrap();
+        kernel.page_tables[1022] = 0;
+        Self::invalidate_tlb(0xFF800000);
+        Self::invalidate_tlb(0xFFBFF000);
+    }
+
+    /// Unmaps the page at virt_addr and frees its frame. Does nothing if it isn't mapped.
+    /// Only use this on pages that own their frame (so not on the kernel's identity mapped pages, for example)
+    pub unsafe fn free_page(&mut self, virt_addr: usize) {
+        let mut usage = FRAMES_USAGE.lock();
+        let in_kernel: bool = self.map_foreign();
+
+        let (index, _, table) = self.find_table(virt_addr);
+        if !table.is_null() && (*table).pages[index].present() {
+            let page: &mut Page = &mut (*table).pages[index];
+            release_frame(&mut usage, page);
+            *page = Page(0);
+            if !in_kernel {
+                Self::invalidate_tlb(virt_addr);
+            }
+        }
+
+        if in_kernel {
+            Self::unmap_foreign();
+        }
+    }
+
+    /// Unmaps the page at virt_addr in the active directory without freeing its frame (e.g. to make part of the heap a guard page)
+    pub unsafe fn unmap_page(&mut self, virt_addr: usize) {
+        assert!(core::ptr::eq(self, Self::curr()));
+        if let Some(page) = self.get_page(virt_addr) {
+            *page = Page(0);
+            Self::invalidate_tlb(virt_addr);
+        }
+    }
+
+    /// Makes the (already mapped) page at virt_addr in the active directory writable or read only
+    pub unsafe fn set_writable(&mut self, virt_addr: usize, writable: bool) {
+        assert!(core::ptr::eq(self, Self::curr()));
+        if let Some(page) = self.get_page(virt_addr) {
+            page.set_rw(writable);
+            Self::invalidate_tlb(virt_addr);
+        }
+    }
+
+    /// Returns a copy of this (active) directory for a forked process. Rather than copying the process' memory,
+    /// both directories map the same frames read only, and whichever writes to a page first gets its own copy of it
+    /// (see handle_cow_fault). Must be manually freed, via Box::from_raw
+    pub unsafe fn fork(&mut self) -> *mut PageDirectory {
+        assert!(core::ptr::eq(self, Self::curr()));
+        let child: &mut PageDirectory = Self::new().as_mut().unwrap();
+
+        // Skip 1022 and 1023, since they're the foreign and recursive mappings
+        for i in 0..PAGE_ENTRIES - 2 {
+            if SHARED_TABLES.contains(&i) || self.page_tables[i] & PageFlags::PRESENT.bits() == 0 {
+                continue;
+            }
+
+            let table: *mut PageTable = self.get_table_ptr(i);
+            for j in 0..PAGE_ENTRIES {
+                let page: &mut Page = &mut (*table).pages[j];
+                let frame = page.frame() as usize;
+                if !page.present() || !owns_frame(frame) {
+                    continue; // either nothing, or kernel memory which the child already has
+                }
+
+                let addr = (i * PAGE_ENTRIES + j) * PAGE_SIZE;
+                let mut flags = PageFlags::USER;
+                if page.rw() || page.cow() {
+                    // Writable pages stop being writable in both directories until they're copied
+                    flags |= PageFlags::COW;
+                    page.set_rw(false);
+                    page.set_cow(true);
+                    Self::invalidate_tlb(addr);
+                }
+
+                child.make_page(addr, frame * PAGE_SIZE, flags).unwrap();
+                // (We can't hold FRAMES_USAGE here, since this might have to allocate, and the heap might have to grow)
+                *FRAME_REFS.lock().entry(frame).or_insert(0) += 1;
+            }
+        }
+        child
+    }
+
     // Returns the first free virtual address, or None if none is available
     pub fn get_free_page(&mut self) -> Option<usize> {
         let tables: &[u32; PAGE_ENTRIES] = unsafe { &(*self.get_dir_ptr()).page_tables };
@@ -308,31 +431,137 @@ impl PageDirectory {
 
 impl Drop for PageDirectory {
     fn drop(&mut self) {
+        assert!(!core::ptr::eq(self, Self::curr()), "Can't free the active page directory!");
         let mut usage = FRAMES_USAGE.lock();
- 
-        // Don't free the last table, as it points at the directory (see map_recursive)
-        for table in self.page_tables.iter().take(self.page_tables.len() - 1) {
-            let t = (table & 0xFFFFF000) as usize; // physical address of the table
-
-            // Free any table that was allocated on the actual heap (as opposed to the kernel heap)
-            // (The kernel heap is a bump allocator and therefore freeing is useless)
-            if t != 0 && t > unsafe { HEAP_END } {
-                unsafe {
+
+        unsafe {
+            // We need to be able to read the tables in order to free the frames inside of them
+            let in_kernel: bool = self.map_foreign();
+
+            // Don't free the last table, as it points at the directory (see map_recursive)
+            for i in 0..self.page_tables.len() - 1 {
+                let t = (self.page_tables[i] & 0xFFFFF000) as usize; // physical address of the table
+
+                // The shared tables belong to the kernel's directory
+                if t == 0 || SHARED_TABLES.contains(&i) {
+                    continue;
+                }
+
+                // Free the memory inside of the table, except for the kernel's identity mapped pages,
+                // which every single directory maps.
+                let table: *mut PageTable = self.get_table_ptr(i);
+                for page in (*table).pages.iter_mut() {
+                    if page.present() && owns_frame(page.frame() as usize) {
+                        release_frame(&mut usage, page);
+                    }
+                }
+
+                // Free any table that was allocated on the actual heap (as opposed to the kernel heap)
+                // (The kernel heap is a bump allocator and therefore freeing is useless)
+                if t > HEAP_END {
                     usage.set_frame_used(t / PAGE_SIZE, false);
                 }
             }
 
-            // Note: we don't free the memory *inside* of the tables since a single frame could
-            // be mapped to several different directories (this happens for example with the kernel,
-            // which every single directory maps).
-            // Processes should free all memory they allocate....
+            if in_kernel {
+                Self::unmap_foreign();
+            }
+        }
+    }
+}
+
+/// How many more directories map each frame besides the first one, for frames that are shared because of fork.
+/// Frames that aren't shared (almost all of them) aren't in here at all.
+static FRAME_REFS: spin::Mutex<BTreeMap<usize, usize>> = spin::Mutex::new(BTreeMap::new());
+
+/// Frees page's frame, unless some other directory still maps it, in which case we just forget about it
+unsafe fn release_frame(usage: &mut FramesUsage, page: &mut Page) {
+    let frame = page.frame() as usize;
+    let mut refs = FRAME_REFS.lock();
+    match refs.get_mut(&frame) {
+        Some(count) => {
+            *count -= 1;
+            if *count == 0 {
+                refs.remove(&frame);
+            }
+            page.set_frame(0);
+        }
+        None => usage.free_frame(page),
+    }
+}
+
+/// Scratch space for copying a page in handle_cow_fault, since the new frame isn't mapped anywhere until the old one is unmapped
+static mut COW_BUFFER: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+
+/// Called on a page fault caused by writing to a present page in the active directory.
+/// If it's a copy on write page, it gets its own (writable) frame and true is returned.
+/// Otherwise the access is simply invalid, and false is returned.
+pub(crate) unsafe fn handle_cow_fault(addr: usize) -> bool {
+    let page_addr = addr & !(PAGE_SIZE - 1);
+    let dir: &mut PageDirectory = PageDirectory::curr().as_mut().unwrap();
+    let Some(page) = dir.get_page(page_addr) else { return false; };
+    if !page.present() || !page.cow() {
+        return false;
+    }
+
+    let frame = page.frame() as usize;
+    let shared = {
+        let mut refs = FRAME_REFS.lock();
+        match refs.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                if *count == 0 {
+                    refs.remove(&frame);
+                }
+                true
+            }
+            None => false,
         }
+    };
+
+    // If nobody else maps the frame anymore, it's ours and we can simply write to it. Otherwise we copy it.
+    let buffer = &mut *core::ptr::addr_of_mut!(COW_BUFFER);
+    if shared {
+        core::ptr::copy_nonoverlapping(page_addr as *const u8, buffer.as_mut_ptr(), PAGE_SIZE);
+        let mut usage = FRAMES_USAGE.lock();
+        let new_frame = usage.get_free_frame();
+        usage.set_frame_used(new_frame, true);
+        page.set_frame(new_frame as u32);
     }
+
+    page.set_rw(true);
+    page.set_cow(false);
+    PageDirectory::invalidate_tlb(page_addr);
+
+    if shared {
+        core::ptr::copy_nonoverlapping(buffer.as_ptr(), page_addr as *mut u8, PAGE_SIZE);
+    }
+    true
 }
 
-// A struct in which each bit corresponds to whether its frame is used (1) or not (0)
-#[repr(align(4))]
-pub struct FramesUsage([u32; 32768]); // 32768 - covers all possible addresses for 32bit
+/// Whether a page mapped to frame belongs to its directory alone, and should be freed with it
+fn owns_frame(frame: usize) -> bool {
+    let addr = frame * PAGE_SIZE;
+    // Everything below HEAP_LIMIT is either identity mapped kernel memory or the (shared) heap
+    addr >= HEAP_LIMIT && !(kernel_start()..kernel_end()).contains(&addr)
+}
+
+/// A snapshot of how physical memory is used, in frames
+#[derive(Clone, Copy, Default)]
+pub struct MemInfo {
+    /// The number of frames of usable RAM
+    pub total_frames: usize,
+    /// The number of usable frames that aren't currently in use
+    pub free_frames: usize,
+    /// The number of frames the memory map marks as reserved, ACPI, bad, etc.
+    pub reserved_frames: usize,
+}
+
+pub struct FramesUsage {
+    /// Each bit corresponds to whether its frame is used (1) or not (0)
+    bitmap: [u32; 32768], // 32768 - covers all possible addresses for 32bit
+    info: MemInfo,
+}
 
 impl FramesUsage {
     const fn get_idx_off(frame_idx: usize) -> (usize, usize) {
@@ -341,31 +570,108 @@ impl FramesUsage {
 
     unsafe fn set_frame_used(&mut self, frame_idx: usize, value: bool) {
         let (idx, off) = Self::get_idx_off(frame_idx);
+        let was_used = self.bitmap[idx] & (0x1 << off) != 0;
         if value {
-            self.0[idx] |= 0x1 << off;
+            self.bitmap[idx] |= 0x1 << off;
         } else {
-            self.0[idx] &= !(0x1 << off);
+            self.bitmap[idx] &= !(0x1 << off);
+        }
+
+        if was_used && !value {
+            self.info.free_frames += 1;
+        } else if !was_used && value {
+            self.info.free_frames -= 1;
+        }
+    }
+
+    /// Marks every frame that is at least partially inside of addr..addr+len as used (or unused)
+    unsafe fn set_range_used(&mut self, addr: usize, len: usize, value: bool) {
+        let first = addr / PAGE_SIZE;
+        let last = (addr + len).div_ceil(PAGE_SIZE);
+        for frame in first..last {
+            self.set_frame_used(frame, value);
+        }
+    }
+
+    /// Builds the bitmap from the memory map. Anything GRUB doesn't explicitly call available is considered used.
+    unsafe fn init(&mut self, info: &crate::grub::MultibootInfo) {
+        self.bitmap.fill(0xFFFFFFFF);
+        self.info = MemInfo::default();
+
+        const MAX_ADDR: u64 = 1 << 32;
+        match info.memory_map() {
+            Some(map) => for entry in map {
+                let (addr, len, entry_type) = (entry.addr, entry.len, entry.entry_type);
+                if addr >= MAX_ADDR {
+                    continue; // we can't address it anyways
+                }
+                let len = u64::min(len, MAX_ADDR - addr) as usize;
+                let addr = addr as usize;
+
+                if entry_type == crate::grub::MEMORY_AVAILABLE {
+                    // Only whole frames are usable
+                    let first = addr.div_ceil(PAGE_SIZE);
+                    let last = (addr + len) / PAGE_SIZE;
+                    for frame in first..last {
+                        self.set_frame_used(frame, false);
+                    }
+                    self.info.total_frames += last.saturating_sub(first);
+                } else {
+                    self.info.reserved_frames += len.div_ceil(PAGE_SIZE);
+                }
+            },
+            None => {
+                // Without a memory map, all we know is that there are mem_upper free KBs at address 0x100_000
+                self.set_range_used(0x100_000, info.mem_upper * 1024, false);
+                self.info.total_frames = info.mem_upper * 1024 / PAGE_SIZE;
+            }
         }
+
+        // Everything up until HEAP_END is the BIOS, GRUB and the paging heap
+        self.set_range_used(0, HEAP_END, true);
+        // And of course the kernel itself
+        self.set_range_used(kernel_start(), kernel_end() - kernel_start(), true);
+    }
+
+    pub fn is_frame_used(&self, frame_idx: usize) -> bool {
+        let (idx, off) = Self::get_idx_off(frame_idx);
+        self.bitmap[idx] & (0x1 << off) != 0
+    }
+
+    pub fn info(&self) -> MemInfo {
+        self.info
     }
 
-    /// Returns the index of the first unused frame
+    /// Returns the index of an unused frame: the first one after HEAP_LIMIT, since everything before belongs to the heap.
+    /// Machines with less memory than that don't have any, so then it's the last one before it. The heap grows upwards
+    /// (and skips frames that are already used, see heap::FreeList::grow), so it gets to those last
     pub fn get_free_frame(&self) -> usize {
+        let heap_words = (HEAP_LIMIT / PAGE_SIZE).div_ceil(32);
         // note: this is performance critical code. .into_iter().enumerate() is about 3 times slower.
-        for i in unsafe { (HEAP_END / PAGE_SIZE).div_ceil(32) }..self.0.len() { // We only start checking at HEAP_END since everything before is guaranteed to be in use
-            let frame = self.0[i];
-            // if every bit in frame is set, we don't need to check each bit individually
-            if frame == 0xFFFFFFFF {
-                continue;
+        for i in heap_words..self.bitmap.len() {
+            if let Some(frame) = self.free_frame_in(i) {
+                return frame;
             }
-            for j in 0..32 {
-                if frame & (0x1 << j) == 0 {
-                    return i * 32 + j;
-                }
+        }
+        for i in (0..heap_words).rev() {
+            if let Some(frame) = self.free_frame_in(i) {
+                return frame;
             }
         }
         panic!("All frames are used");
     }
 
+    /// Returns the index of an unused frame out of the 32 in bitmap[i], if there is one
+    #[inline]
+    fn free_frame_in(&self, i: usize) -> Option<usize> {
+        let frame = self.bitmap[i];
+        // if every bit in frame is set, we don't need to check each bit individually
+        if frame == 0xFFFFFFFF {
+            return None;
+        }
+        (0..32).find(|j| frame & (0x1 << j) == 0).map(|j| i * 32 + j)
+    }
+
     /// Sets the page's frame to frame
     pub unsafe fn set_page_frame(&mut self, page: &mut Page, frame: usize) {
         if page.frame() != 0 {
@@ -385,10 +691,32 @@ impl FramesUsage {
     }
 }
 
-pub(crate) static FRAMES_USAGE: spin::Mutex<FramesUsage> = spin::Mutex::new(FramesUsage([0; 32768]));
+/// Allocates every page table covering addr..addr+size in the kernel's directory ahead of time.
+/// Directories created afterwards point at these very same tables, so whatever gets mapped there later
+/// (e.g. when the heap grows) is immediately visible from every address space.
+/// addr and size must be aligned to PAGE_SIZE * PAGE_ENTRIES, since a table can't be partially shared.
+pub(crate) unsafe fn share_tables(addr: usize, size: usize) {
+    let kernel: &mut PageDirectory = KERNEL_DIR.as_mut().unwrap();
+    let first = addr / (PAGE_SIZE * PAGE_ENTRIES);
+    let last = (addr + size) / (PAGE_SIZE * PAGE_ENTRIES);
+
+    let mut usage = FRAMES_USAGE.lock();
+    for i in first..last {
+        if kernel.page_tables[i] & PageFlags::PRESENT.bits() == 0 {
+            kernel.make_table(&mut usage, i);
+        }
+    }
+    SHARED_TABLES = first..last;
+}
+
+pub(crate) static FRAMES_USAGE: spin::Mutex<FramesUsage> = spin::Mutex::new(FramesUsage {
+    bitmap: [0; 32768],
+    info: MemInfo { total_frames: 0, free_frames: 0, reserved_frames: 0 },
+});
 
-/// Initialises and enables paging. Returns the address at which the heap should begin
-pub fn init() -> usize {
+/// Initialises and enables paging, and starts tracking which frames are used according to info's memory map.
+/// Returns the address at which the heap should begin
+pub(crate) fn init(info: &crate::grub::MultibootInfo) -> usize {
     // Create a page directory for the kernel.
     // PageDirectory::new cannot be used, nor can Box, since there's no allocator yet
     let kernel_dir: &mut PageDirectory = unsafe {
@@ -402,27 +730,33 @@ pub fn init() -> usize {
         // The only instances of using kmalloc after this are when allocating a single PageTable.
         // Theoretically there can only be PAGE_ENTRIES of them, so
         HEAP_END = PLACEMENT_ADDR + size_of::<PageTable>() * PAGE_ENTRIES;
+
+        // We've used the beginning of the "proper" heap with kmalloc, and we don't want to override anything.
+        // Returning the first free address is the simplest solution.
+        // We return the first free block (multiple of PAGE_SIZE) and not the first address to avoid double mapping
+        let x = HEAP_END + 1; // + 1 so that if it's already aligned to 4K we return the next block anyways
+        HEAP_END = x + PAGE_SIZE - (x % PAGE_SIZE); // actually align it
+
+        // Now that we know where everything is, we can figure out which frames are actually free
+        FRAMES_USAGE.lock().init(info);
     }
 
     kernel_dir.map_kernel(PageFlags::RW);
     kernel_dir.map_recursive(PageFlags::RW);
 
+    // The double fault task switches directories, and needs one that's always there
+    crate::userspace::set_double_fault_dir(kernel_dir as *const _ as u32);
+
     unsafe {
+        KERNEL_DIR = kernel_dir;
         // Activate the directory and actually enable paging CPU side
         kernel_dir.switch_to();
         asm!(
             "mov eax, cr0",
-            "or eax, 0x80000000",
+            "or eax, 0x80010000", // PG, and WP so that the kernel can't write to read only (e.g. copy on write) pages either
             "mov cr0, eax"
         );
     }
 
-    // We've used the beginning of the "proper" heap with kmalloc, and we don't want to override anything.
-    // Returning the first free address is the simplest solution.
-    // We return the first free block (multiple of PAGE_SIZE) and not the first address to avoid double mapping
-    unsafe {
-        let x = HEAP_END + 1; // + 1 so that if it's already aligned to 4K we return the next block anyways
-        HEAP_END = x + PAGE_SIZE - (x % PAGE_SIZE); // actually align it
-        HEAP_END
-    }
+    unsafe { HEAP_END }
 }
@@ -0,0 +1,134 @@
+use alloc::{collections::VecDeque, sync::Arc};
+use spin::Mutex;
+
+use crate::{fd::IoError, process::WaitQueue};
+
+/// How many bytes a pipe holds before writers have to wait for a reader
+pub const PIPE_SIZE: usize = 4096;
+
+/// A bounded buffer in the kernel. Whatever is written to one end can be read from the other, in the same order
+pub struct Pipe {
+    state: Mutex<PipeState>,
+    /// Readers waiting for data (or for the last writer to go away)
+    readable: WaitQueue,
+    /// Writers waiting for room (or for the last reader to go away)
+    writable: WaitQueue,
+}
+
+struct PipeState {
+    buffer: VecDeque<u8>,
+    /// How many read ends are open
+    readers: usize,
+    /// How many write ends are open. Once it's 0, reading an empty pipe returns 0 (EOF)
+    writers: usize,
+}
+
+/// Creates a new pipe, and returns its two ends. Fails if there isn't enough memory for it (its whole buffer is allocated upfront)
+pub fn new() -> Result<(PipeReader, PipeWriter), IoError> {
+    let mut buffer = VecDeque::new();
+    buffer.try_reserve_exact(PIPE_SIZE).map_err(|_| IoError::OutOfMemory)?;
+    let pipe = Arc::try_new(Pipe {
+        state: Mutex::new(PipeState { buffer, readers: 1, writers: 1 }),
+        readable: WaitQueue::new(),
+        writable: WaitQueue::new(),
+    }).map_err(|_| IoError::OutOfMemory)?;
+    Ok((PipeReader(pipe.clone()), PipeWriter(pipe)))
+}
+
+impl Pipe {
+    /// Reads as much as is available (up to buffer.len()), blocking until there's something to read.
+    /// Returns 0 once the pipe is empty and every write end is closed. Must be called with interrupts disabled
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        if buffer.is_empty() {
+            return 0;
+        }
+        loop {
+            let mut state = self.state.lock();
+            if !state.buffer.is_empty() {
+                let count = usize::min(buffer.len(), state.buffer.len());
+                for (dst, src) in buffer.iter_mut().zip(state.buffer.drain(..count)) {
+                    *dst = src;
+                }
+                drop(state);
+                self.writable.wake_all();
+                return count;
+            }
+            if state.writers == 0 {
+                return 0;
+            }
+            // Interrupts are disabled, so nobody can write between the check and us waiting
+            drop(state);
+            self.readable.wait();
+        }
+    }
+
+    /// Writes all of data, blocking whenever the pipe is full. Fails if there aren't any read ends left (even midway).
+    /// Must be called with interrupts disabled
+    pub fn write(&self, data: &[u8]) -> Result<usize, IoError> {
+        let mut written = 0;
+        while written < data.len() {
+            let mut state = self.state.lock();
+            if state.readers == 0 {
+                // Whatever made it in before the readers left still counts
+                return if written > 0 { Ok(written) } else { Err(IoError::BrokenPipe) };
+            }
+            let count = usize::min(PIPE_SIZE - state.buffer.len(), data.len() - written);
+            if count == 0 {
+                drop(state);
+                self.writable.wait();
+                continue;
+            }
+            state.buffer.extend(&data[written..written + count]);
+            written += count;
+            drop(state);
+            self.readable.wake_all();
+        }
+        Ok(written)
+    }
+}
+
+/// The read end of a pipe. Every clone counts as another open read end
+pub struct PipeReader(pub Arc<Pipe>);
+
+/// The write end of a pipe. Every clone counts as another open write end
+pub struct PipeWriter(pub Arc<Pipe>);
+
+impl Clone for PipeReader {
+    fn clone(&self) -> Self {
+        self.0.state.lock().readers += 1;
+        PipeReader(self.0.clone())
+    }
+}
+
+impl Clone for PipeWriter {
+    fn clone(&self) -> Self {
+        self.0.state.lock().writers += 1;
+        PipeWriter(self.0.clone())
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.readers -= 1;
+        let last = state.readers == 0;
+        drop(state);
+        // Blocked writers have nobody to write to anymore
+        if last {
+            self.0.writable.wake_all();
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock();
+        state.writers -= 1;
+        let last = state.writers == 0;
+        drop(state);
+        // Blocked readers get EOF
+        if last {
+            self.0.readable.wake_all();
+        }
+    }
+}
@@ -1,137 +1,984 @@
-use alloc::{vec::Vec, boxed::Box};
-use core::{arch::asm, ptr::NonNull};
+use alloc::{vec::Vec, boxed::Box, sync::Arc, collections::{BTreeSet, BTreeMap}, alloc::{alloc, dealloc}};
+use core::{alloc::Layout, arch::asm, mem::size_of};
 use spin::Mutex;
+use crate::{interrupts::TrapFrame, errno::Errno, fd::{FdTable, Handle}, fs::{self, Inode}, paging::{PageDirectory, PageFlags, PAGE_SIZE}};
 
-/// The context of the CPU at the time of process switching.
-/// These are the only registers we need since the interrupt handler saves the rest of the state based on the stack.
-#[repr(C, packed)]
+/// A process ID. Stays the same throughout the lifetime of the process, and is never reused.
+/// Threads get one as well (their thread ID), and a process' PID is the one of its first thread.
+pub type Pid = u32;
+
+/// What a user thread runs (see thread_create). Its return value is its exit status
+pub type ThreadEntry = extern "C" fn(arg: usize) -> i32;
+
+/// The exit status of a process the kernel had to kill (e.g. because it caused a page fault)
+pub const KILLED_EXIT_STATUS: i32 = -1;
+
+/// The size of a process' kernel stack, not including its guard page
+const KERNEL_STACK_SIZE: usize = 4 * PAGE_SIZE;
+
+/// The stack a process uses whenever it's in ring 0 (syscalls, interrupts, etc.). Comes from the heap, so every directory maps it.
+/// Its lowest page is unmapped, so that overflowing it page faults instead of silently overwriting whatever comes before it.
+pub struct KernelStack {
+    /// The start of the allocation, i.e. the guard page
+    bottom: *mut u8,
+}
+
+impl KernelStack {
+    fn layout() -> Layout {
+        Layout::from_size_align(PAGE_SIZE + KERNEL_STACK_SIZE, PAGE_SIZE).unwrap()
+    }
+
+    pub fn new() -> Self {
+        unsafe {
+            let bottom = alloc(Self::layout());
+            assert!(!bottom.is_null(), "Out of memory for a kernel stack");
+            // The heap's tables are shared by every directory, so it doesn't matter which one is active
+            (*PageDirectory::curr()).unmap_page(bottom as usize);
+            KernelStack { bottom }
+        }
+    }
+
+    /// The first address after the stack, i.e. where esp starts
+    pub fn top(&self) -> usize { self.bottom as usize + PAGE_SIZE + KERNEL_STACK_SIZE }
+
+    /// Puts frame at the very top of the stack, and returns its address.
+    /// Resuming a process from there pops the frame and leaves the stack empty.
+    unsafe fn push_frame(&mut self, frame: TrapFrame) -> *mut TrapFrame {
+        let ptr = (self.top() - size_of::<TrapFrame>()) as *mut TrapFrame;
+        ptr.write(frame);
+        ptr
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        unsafe {
+            // The heap expects all of its memory to be mapped (and identity mapped, at that)
+            let guard = self.bottom as usize;
+            (*PageDirectory::curr()).make_page(guard, guard, PageFlags::RW).unwrap();
+            dealloc(self.bottom, Self::layout());
+        }
+    }
+}
+
+/// Everything needed to resume a thread that isn't running
 pub struct Context {
-    pub esp: u32,
-    pub eip: u32,
-    pub dir: *mut crate::paging::PageDirectory
+    /// Where the thread's TrapFrame is on its kernel stack. Only up to date while it isn't running
+    pub esp: *mut TrapFrame,
+    /// The frame of the syscall the thread is in the middle of, if any (see syscall::curr_frame). Only up to date while it
+    /// isn't running, like esp
+    pub syscall_frame: *mut TrapFrame,
+    /// Shared by every thread of the process
+    pub dir: *mut PageDirectory,
+    pub stack: KernelStack,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    /// Currently executing
+    Running,
+    /// Waiting for its turn
+    Ready,
+    /// Waiting for something to happen. Won't be scheduled until then
+    Blocked,
+    /// Done executing, but its parent hasn't collected its exit status yet
+    Zombie,
 }
 
-#[derive(Clone, Copy)]
+/// A single thread of execution. A process is every thread with the same group, all sharing one directory
 pub struct Process {
-    pub ctx: NonNull<Context>,
-    // pub data: crate::heap::ProcessHeapData
+    pub pid: Pid,
+    /// The process that created this one, or 0 if it was the kernel (or the parent already exited).
+    /// Always 0 for threads other than the first, since they aren't anyone's children
+    pub parent: Pid,
+    /// The PID of the process this thread belongs to, i.e. of its first thread
+    pub group: Pid,
+    pub state: State,
+    /// Only meaningful once the process is a zombie. For threads other than the first, it's what thread_join returns
+    pub exit_status: i32,
+    /// The context to restore when switching to this process. None once it's a zombie
+    pub ctx: Option<Context>,
+    /// From MIN_NICE to MAX_NICE. Lower values get scheduled first (see priority)
+    pub nice: i32,
+    /// The process' feedback queue. It starts at 0, and moves down every time it uses up a whole slice
+    pub level: usize,
+    /// How many more ticks the process can run before someone else gets a turn. 0 if it needs a new slice
+    pub slice_left: u32,
+    pub times: ProcessTimes,
+    /// The process' open descriptors. Shared by all of its threads
+    pub fds: Arc<Mutex<FdTable>>,
+    /// Whether the process may make privileged syscalls, e.g. talk to the hardware (see Syscall::is_privileged).
+    /// Only processes the kernel starts itself are, along with their forks. Running another program drops it
+    pub privileged: bool,
+    /// Whether every syscall the process makes is logged (see syscall.rs). Processes it creates are traced as well
+    pub traced: bool,
+    /// The working directory, where relative paths start from (see fs::chdir). Shared by all of the process' threads
+    pub cwd: Inode,
+    /// A copy of a handle the thread is using right now, e.g. a pipe it's blocked on (see fd::with_handle).
+    /// It's dropped along with the thread if it never gets to finish
+    pub in_use: Option<Box<Handle>>,
+    /// The wait queue the thread is blocked in, if any (see WaitQueue::wait). If the thread goes away without being woken,
+    /// it's taken out of it (see forget_blocked)
+    pub blocked_on: Option<*const WaitQueue>,
 }
 
 impl Process {
-    pub fn new(ctx: *mut Context) -> Self { Process {
-        ctx: NonNull::new(ctx).unwrap(),
-        // data: crate::heap::ProcessHeapData::new()
-    }}
+    /// Lower is more important. Ready processes with the lowest priority get to run first
+    fn priority(&self) -> usize {
+        self.level + ((self.nice - MIN_NICE) / NICE_PER_LEVEL) as usize
+    }
+}
+
+/// How many ticks a process has spent running, in user and kernel mode
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ProcessTimes {
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
 }
 
+pub const MIN_NICE: i32 = -20;
+pub const MAX_NICE: i32 = 19;
+/// How much nicer a process has to be to count as a whole level lower
+const NICE_PER_LEVEL: i32 = 10;
+
+/// The number of feedback queues
+const LEVELS: usize = 4;
+/// Every this many ticks, every process goes back to the first level, so that the CPU hungry ones don't starve
+const BOOST_INTERVAL: u64 = 100;
+/// The length (in ticks) of a slice at the first level. Every level after it gets twice as much as the one before it
+static mut QUANTUM: u32 = 1;
+
 unsafe impl Sync for Process {}
 unsafe impl Send for Process {}
 
 static PROCESSES: Mutex<Vec<Process>> = Mutex::new(Vec::new());
+/// The index of the running process in PROCESSES
 static CURR_INDEX: Mutex<usize> = Mutex::new(0);
+static NEXT_PID: Mutex<Pid> = Mutex::new(1);
 
-fn next_index(curr_index: usize, proc_len: usize) -> usize {
-    if curr_index < proc_len - 1 {
-        curr_index + 1
-    } else {
-        0
+/// Returns the index of the ready process with the best priority. Out of the ones that share it,
+/// it's the first one when searching (circularly) from start, so that they take turns.
+fn find_ready(processes: &[Process], start: usize) -> Option<usize> {
+    (0..processes.len())
+        .map(|i| (start + i) % processes.len())
+        .filter(|&i| processes[i].state == State::Ready)
+        .min_by_key(|&i| processes[i].priority()) // min_by_key returns the first of the minimums
+}
+
+/// Called by the timer on every tick, with the frame it interrupted. Charges the tick to the running process, and switches
+/// to another one if its slice is over (or a more important one is ready). Returns the frame to resume, like next_program
+pub(crate) fn tick(frame: *mut TrapFrame) -> *mut TrapFrame {
+    {
+        let mut processes = PROCESSES.lock();
+        if crate::timer::get_ticks() % BOOST_INTERVAL == 0 {
+            for p in processes.iter_mut() {
+                p.level = 0;
+            }
+        }
+
+        if !is_idle() && processes.len() != 0 {
+            let curr_index = *CURR_INDEX.lock();
+            let curr = &mut processes[curr_index];
+            if unsafe { (*frame).from_user() } {
+                curr.times.user_ticks += 1;
+            } else {
+                curr.times.kernel_ticks += 1;
+            }
+
+            curr.slice_left = curr.slice_left.saturating_sub(1);
+            if curr.slice_left == 0 {
+                curr.level = usize::min(curr.level + 1, LEVELS - 1);
+            } else {
+                // There's still time left, unless someone more important is waiting
+                let priority = curr.priority();
+                if !processes.iter().any(|p| p.state == State::Ready && p.priority() < priority) {
+                    return frame;
+                }
+            }
+        }
     }
+    next_program(frame)
 }
 
-fn prev_index(curr_index: usize, proc_len: usize) -> usize {
-    if curr_index > 0 {
-        curr_index - 1
+/// Marks processes[i] as the running process, and gives it a new slice if it used up its last one
+fn run(processes: &mut [Process], i: usize) {
+    *CURR_INDEX.lock() = i;
+    let p = &mut processes[i];
+    p.state = State::Running;
+    if p.slice_left == 0 {
+        p.slice_left = unsafe { QUANTUM } << p.level;
+    }
+}
+
+/// Called by the timer with the frame of the code it interrupted (on the current process' kernel stack).
+/// Saves it as the current process' context, and switches to the next ready process (or the idle task, if there isn't one).
+/// Returns the frame to resume, which is either the one we got or the next process' one (on its own kernel stack).
+pub(crate) fn next_program(frame: *mut TrapFrame) -> *mut TrapFrame {
+    let mut processes = PROCESSES.lock();
+    if processes.len() == 0 { return frame; }
+    let curr_index = *CURR_INDEX.lock();
+
+    // Save our context
+    if is_idle() {
+        idle_ctx().esp = frame;
+        idle_ctx().syscall_frame = crate::syscall::curr_frame();
     } else {
-        proc_len - 1
+        let curr = &mut processes[curr_index];
+        let ctx = curr.ctx.as_mut().unwrap();
+        ctx.esp = frame;
+        ctx.syscall_frame = crate::syscall::curr_frame();
+        if curr.state == State::Running {
+            curr.state = State::Ready;
+        }
+    }
+
+    // The most important ready process, starting after us (which might be us again). If there isn't any, we idle.
+    let next: *const Context = match find_ready(&processes, curr_index + 1) {
+        Some(i) => {
+            run(&mut processes, i);
+            unsafe { RUNNING_IDLE = false; }
+            processes[i].ctx.as_ref().unwrap()
+        }
+        None => {
+            unsafe { RUNNING_IDLE = true; }
+            idle_ctx()
+        }
+    };
+    unsafe {
+        activate(&*next);
+        (*next).esp
     }
 }
 
-/// Switches from the current program to the next one, while updating the context of the current one
-pub(crate) fn next_program(new_context: *mut Context) {
-    let mut new_process: Process;
-    // Since we jump out of this function unbeknownst to the compiler (via the `ret`), we add an artificial scope.
-    {
-        let mut processes = PROCESSES.lock();
-        if processes.len() == 0 { return; }
-        let mut curr_index = CURR_INDEX.lock();
+/// Set while the idle task is running. CURR_INDEX is meaningless then
+static mut RUNNING_IDLE: bool = false;
+/// The context of the idle task. Created along with the first process
+static mut IDLE_CTX: Option<Context> = None;
 
-        // Assign our context to the previous index
-        let replace = prev_index(*curr_index, processes.len());
-        unsafe { drop(Box::from_raw(processes[replace].ctx.as_ptr())); } // free previous context
-        processes[replace] = Process::new(new_context);
+fn idle_ctx() -> &'static mut Context {
+    unsafe { (*core::ptr::addr_of_mut!(IDLE_CTX)).as_mut().unwrap() }
+}
 
-        new_process = processes[*curr_index];
+/// Whether the CPU is idle, i.e. no process is ready to run
+pub fn is_idle() -> bool { unsafe { RUNNING_IDLE } }
 
-        *curr_index = next_index(*curr_index, processes.len());
-    }
+/// What runs whenever no process is ready. It isn't a process itself: it runs in ring 0, in the kernel's directory,
+/// and is only ever switched to by the scheduler.
+fn idle() -> ! {
+    // Halting (rather than spinning) lets the CPU actually rest until the next interrupt
+    loop { unsafe { asm!("sti", "hlt"); } }
+}
+
+/// Makes ctx's address space the active one, has the CPU use its kernel stack for the next trap from user mode,
+/// and puts back the syscall it was in the middle of
+unsafe fn activate(ctx: &Context) {
+    (*ctx.dir).switch_to();
+    crate::userspace::set_kernel_stack(ctx.stack.top() as u32);
+    crate::syscall::set_curr_frame(ctx.syscall_frame);
+}
+
+/// Switches to ctx and resumes its frame. For when there's no interrupted frame to return through (see next_program)
+unsafe fn enter_context(ctx: *const Context) -> ! {
+    activate(&*ctx);
+    asm!(
+        // Pop the frame as if we pushed it ourselves
+        "mov esp, edi",
+        "pop gs",
+        "pop fs",
+        "pop es",
+        "pop ds",
+        "popad",
+        "iretd",
+        in("edi") (*ctx).esp,
+        options(noreturn)
+    );
+}
+
+/// Ends the currently running process (all of its threads): frees its memory, leaves its exit status for its parent,
+/// and switches to the next one. Must be called with interrupts disabled (as syscalls and exception handlers are).
+pub(crate) fn exit(status: i32) -> ! {
+    finish_on_boot_stack(status, true);
+}
+
+/// Ends the current thread, leaving status for thread_join. If it was the last thread of its process, the process exits
+/// with status. Must be called with interrupts disabled, like exit
+pub(crate) fn thread_exit(status: i32) -> ! {
+    finish_on_boot_stack(status, false);
+}
+
+fn finish_on_boot_stack(status: i32, whole_process: bool) -> ! {
+    // We're running on the thread's kernel stack, which we're about to free. So we finish on the boot stack,
+    // which nothing uses anymore once processes are running.
     unsafe {
-        (*new_process.ctx.as_mut().dir).switch_to();
         asm!(
-            // Restore stack
-            "mov esp, [edi]",
-            // Restore eip
-            "push [edi+4]", // Push eip
-            "ret", // jmp to the pushed eip
-            in("edi") new_process.ctx.as_ptr(),
+            "mov esp, {stack}",
+            "push {whole_process}",
+            "push {status}",
+            "call exit_on_boot_stack",
+            stack = in(reg) &crate::KERNEL_STACK_TOP as *const _ as u32,
+            whole_process = in(reg) whole_process as u32,
+            status = in(reg) status,
             options(noreturn)
         );
     }
 }
 
-static mut HAS_LOADED_PROCESSES: bool = false;
-
-pub(crate) fn register(esp: u32, eip: u32, dir: *mut crate::paging::PageDirectory) {
-    // Since we lock PROCESSES, we can't switch programs while registering one.
-    crate::pic::set_mask(0, true);
+/// Removes every process f returns true for, keeping curr_index at the same process
+/// (or at whatever came after it, if it was removed)
+fn remove_where(processes: &mut Vec<Process>, curr_index: &mut usize, f: impl Fn(&Process) -> bool) {
+    let mut i = 0;
+    while i < processes.len() {
+        if !f(&processes[i]) {
+            i += 1;
+            continue;
+        }
+        processes.remove(i);
+        if i < *curr_index {
+            *curr_index -= 1;
+        }
+    }
+}
 
-    let context = Box::new(Context { esp, eip, dir });
-    let ptr = Box::into_raw(context);
+/// Whether every thread of the process pid has exited, leaving only its first one behind as a zombie
+fn has_ended(processes: &[Process], pid: Pid) -> bool {
+    processes.iter().all(|p| p.group != pid || (p.pid == pid && p.state == State::Zombie))
+}
 
-    let len: usize;
+#[no_mangle]
+extern "C" fn exit_on_boot_stack(status: i32, whole_process: bool) -> ! {
+    let next: *const Context;
+    let mut closed = FdTable::default();
+    let mut in_use = Vec::new();
+    let mut blocked = Vec::new();
+    let mut waiters = Vec::new();
     {
         let mut processes = PROCESSES.lock();
-        processes.push(Process::new(ptr));
-        len = processes.len();
         let mut curr_index = CURR_INDEX.lock();
-        *curr_index = next_index(*curr_index , len);
+
+        let pid = processes[*curr_index].pid;
+        let group = processes[*curr_index].group;
+        let dir = processes[*curr_index].ctx.as_ref().unwrap().dir;
+        let fds = processes[*curr_index].fds.clone();
+
+        // Nothing is using our kernel stack anymore. If the whole process is exiting, the same goes for its other threads,
+        // since none of them is running.
+        for p in processes.iter_mut().filter(|p| p.pid == pid || (whole_process && p.group == group)) {
+            p.ctx = None;
+            p.state = State::Zombie;
+            p.exit_status = status;
+            // Like closed below, these can only be dropped once we let go of PROCESSES
+            in_use.extend(p.in_use.take());
+            blocked.push((p.pid, p.blocked_on.take()));
+        }
+
+        // The process lives on as long as any of its threads does
+        if processes.iter().all(|p| p.group != group || p.state == State::Zombie) {
+            unsafe {
+                // We're probably still using the process' directory, and we can't free it while it's active.
+                // Every directory maps the kernel, so it doesn't matter which one we switch to.
+                (*crate::paging::kernel_dir()).switch_to();
+
+                // Give back every frame the process used, and then the directory and its tables.
+                // (Kernel threads use the kernel's own directory, which stays)
+                if dir != crate::paging::kernel_dir() {
+                    forget_futexes(dir);
+                    crate::vma::free_areas(dir);
+                    drop(Box::from_raw(dir));
+                }
+            }
+
+            // Closing a pipe might wake someone up, which needs PROCESSES. So we only take the handles out for now
+            closed = core::mem::take(&mut *fds.lock());
+
+            // Nobody can join the other threads anymore. The first one stays behind for the parent, with our status
+            remove_where(&mut processes, &mut curr_index, |p| p.group == group && p.pid != group);
+            let first = processes.iter_mut().find(|p| p.pid == group).unwrap();
+            first.exit_status = status;
+            // Our parent might be waiting for us. Nobody can be waiting for our own children anymore
+            let mut child_waiters = CHILD_WAITERS.lock();
+            if let Some(parent_waiters) = child_waiters.remove(&first.parent) {
+                waiters = parent_waiters;
+            }
+            child_waiters.remove(&group);
+            drop(child_waiters);
+
+            // Our children are orphans now. Nobody is going to wait for them, so the ones that already exited can go away
+            let exited: Vec<Pid> = processes.iter()
+                .filter(|p| p.parent == group && has_ended(&processes, p.pid))
+                .map(|p| p.pid)
+                .collect();
+            remove_where(&mut processes, &mut curr_index, |p| exited.contains(&p.group));
+            for p in processes.iter_mut().filter(|p| p.parent == group) {
+                p.parent = 0;
+            }
+
+            // The same goes for us, if we don't have a parent
+            remove_where(&mut processes, &mut curr_index, |p| p.pid == group && p.parent == 0);
+        }
+    }
+
+    // The other threads might be blocked in a syscall they'll never get back to
+    forget_blocked(&blocked);
+    drop(closed);
+    drop(in_use);
+    // Someone might be waiting to join us
+    THREAD_EXITED.wake_all();
+    for tid in waiters {
+        wake(tid);
+    }
+
+    // Either way, whatever came after us is now at curr_index (or at 0 if we were last)
+    {
+        let mut processes = PROCESSES.lock();
+        let curr_index = *CURR_INDEX.lock();
+        next = match find_ready(&processes, curr_index) {
+            Some(i) => {
+                run(&mut processes, i);
+                processes[i].ctx.as_ref().unwrap()
+            }
+            // Whoever is blocked will be woken by an interrupt eventually
+            None if processes.iter().any(|p| p.state == State::Blocked) => {
+                unsafe { RUNNING_IDLE = true; }
+                idle_ctx()
+            }
+            None => {
+                use core::fmt::Write;
+                let _ = writeln!(crate::vga_console::CONSOLE.lock(), "No processes left to run.");
+                loop { unsafe { asm!("hlt"); } }
+            }
+        };
+    }
+    unsafe { enter_context(next); }
+}
+
+/// The interrupt the kernel raises to give up the CPU. Handled like a timer tick (see timer::on_yield)
+pub(crate) const YIELD_VECTOR: usize = 0x82;
+
+/// Lets the next ready process run. The current one stays ready, and continues once its turn comes again
+pub(crate) fn yield_now() {
+    unsafe { asm!("int 0x82"); }
+}
+
+/// Blocks the current process until wake is called on it, running other processes in the meantime.
+/// Must be called with interrupts disabled, or the wake up might come before we're even blocked.
+pub(crate) fn block_current() {
+    {
+        let mut processes = PROCESSES.lock();
+        let i = *CURR_INDEX.lock();
+        processes[i].state = State::Blocked;
+    }
+    // The scheduler skips blocked processes, so by the time we're back, we've been woken
+    yield_now();
+}
+
+/// Makes a blocked process ready again. Does nothing if it isn't blocked (or doesn't exist anymore)
+pub(crate) fn wake(pid: Pid) {
+    let mut processes = PROCESSES.lock();
+    if let Some(p) = processes.iter_mut().find(|p| p.pid == pid && p.state == State::Blocked) {
+        p.state = State::Ready;
+        // Whoever woke it took it out of whatever it was waiting in
+        p.blocked_on = None;
+    }
+}
+
+/// Takes the threads out of everything they might be waiting in, since they're going away without ever being woken.
+/// Their wait queue might be in something they were using (see Process::in_use), so this comes before that's dropped
+fn forget_blocked(threads: &[(Pid, Option<*const WaitQueue>)]) {
+    for &(tid, queue) in threads {
+        if let Some(queue) = queue {
+            unsafe { (*queue).waiting.lock().retain(|&pid| pid != tid); }
+        }
+    }
+    let gone = |pid: &Pid| threads.iter().any(|&(tid, _)| tid == *pid);
+    SLEEPING.lock().retain(|(_, pid)| !gone(pid));
+    FUTEXES.lock().retain(|_, waiting| {
+        waiting.retain(|pid| !gone(pid));
+        !waiting.is_empty()
+    });
+    CHILD_WAITERS.lock().retain(|_, waiting| {
+        waiting.retain(|pid| !gone(pid));
+        !waiting.is_empty()
+    });
+}
+
+/// Processes waiting for something to happen. Whoever makes it happen wakes them up.
+pub struct WaitQueue {
+    waiting: Mutex<Vec<Pid>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue { waiting: Mutex::new(Vec::new()) }
+    }
+
+    /// Blocks the current process until it's woken by wake_one or wake_all. Must be called with interrupts disabled
+    pub fn wait(&self) {
+        self.waiting.lock().push(curr_tid());
+        PROCESSES.lock()[*CURR_INDEX.lock()].blocked_on = Some(self);
+        block_current();
+    }
+
+    /// Wakes the process that has been waiting the longest. Returns false if there wasn't any
+    pub fn wake_one(&self) -> bool {
+        let mut waiting = self.waiting.lock();
+        if waiting.is_empty() {
+            return false;
+        }
+        wake(waiting.remove(0));
+        true
+    }
+
+    pub fn wake_all(&self) {
+        for pid in self.waiting.lock().drain(..) {
+            wake(pid);
+        }
     }
+}
 
-    unsafe { HAS_LOADED_PROCESSES = true; }
+/// Sleeping processes, ordered by the tick they should wake up at
+static SLEEPING: Mutex<BTreeSet<(u64, Pid)>> = Mutex::new(BTreeSet::new());
 
-    // If this is the first program being run, we need to manually call next_program,
-    // or else it will free the context we just created and never enter the program.
-    if len == 1 {
-        // Since we had to disable the timer, user programs must manually unmask the timer at their start.
-        next_program(ptr);
+/// Blocks the current process for at least ms milliseconds
+pub(crate) fn sleep(ms: u32) {
+    let ticks = crate::timer::ms_to_ticks(ms as u64);
+    if ticks == 0 {
+        yield_now();
+        return;
     }
-    // Otherwise the task scheduler will automatically call it.
-    crate::pic::set_mask(0, false);
+    SLEEPING.lock().insert((crate::timer::get_ticks() + ticks, curr_tid()));
+    block_current();
 }
 
-pub(crate) fn unregister_prev() {
-    // Since we lock PROCESSES, we can't switch programs while unregistering one.
-    crate::pic::set_mask(0, true);
+/// Called by the timer on every tick. Wakes every process whose sleep is over
+pub(crate) fn wake_sleepers(now: u64) {
+    let mut sleeping = SLEEPING.lock();
+    while let Some(&(deadline, pid)) = sleeping.first() {
+        if deadline > now {
+            break;
+        }
+        sleeping.pop_first();
+        wake(pid);
+    }
+}
 
-    let mut curr_index = CURR_INDEX.lock();
-    let processes = PROCESSES.lock();
-    *curr_index = prev_index(*curr_index, processes.len());
-    unsafe { (*(processes[*curr_index].ctx.as_ptr())).eip = kill_process as u32; };
+/// Threads blocked in futex_wait, by the directory and address they're waiting on (in the order they started waiting)
+static FUTEXES: Mutex<BTreeMap<(usize, usize), Vec<Pid>>> = Mutex::new(BTreeMap::new());
 
-    crate::pic::set_mask(0, false);
+/// Blocks the current thread until futex_wake is called on addr, but only if addr still holds expected.
+/// Checking and blocking happen together (interrupts are disabled), so a wake up that comes after the check is never missed.
+/// Fails with EAGAIN if addr didn't hold expected, so we didn't block at all (or EFAULT if the caller can't read addr)
+pub(crate) fn futex_wait(addr: *const u32, expected: u32) -> Result<(), Errno> {
+    // Reading it might fault it in, so it's the first thing we do
+    if crate::uaccess::copy_from_user(addr)? != expected {
+        return Err(Errno::EAGAIN);
+    }
+    let key = (PageDirectory::curr() as usize, addr as usize);
+    FUTEXES.lock().entry(key).or_default().push(curr_tid());
+    block_current();
+    Ok(())
 }
 
-fn kill_process() {
-    let curr;
+/// Wakes up to n threads (of the current process) that are blocked in futex_wait on addr, the ones that waited longest first.
+/// Returns how many were woken
+pub(crate) fn futex_wake(addr: *const u32, n: usize) -> usize {
+    let key = (PageDirectory::curr() as usize, addr as usize);
+    let woken: Vec<Pid> = {
+        let mut futexes = FUTEXES.lock();
+        let Some(waiting) = futexes.get_mut(&key) else {
+            return 0;
+        };
+        let woken = waiting.drain(..usize::min(n, waiting.len())).collect();
+        if waiting.is_empty() {
+            futexes.remove(&key);
+        }
+        woken
+    };
+
+    let count = woken.len();
+    for tid in woken {
+        wake(tid);
+    }
+    count
+}
+
+/// Drops every futex of dir, which is about to be freed. (Whoever was waiting on them is gone along with it)
+fn forget_futexes(dir: *mut PageDirectory) {
+    FUTEXES.lock().retain(|&(d, _), _| d != dir as usize);
+}
+
+/// Makes the current process start over from frame in dir (which must be active), and frees its old address space.
+/// Its PID, parent and children stay the same, but its other threads are gone (the current one takes over as the first),
+/// and it isn't privileged anymore, since it's running a different program. Used by exec
+pub(crate) fn replace_image(frame: TrapFrame, dir: *mut PageDirectory) -> ! {
+    let ctx: *const Context;
+    let old_dir: *mut PageDirectory;
+    let in_use: Vec<Box<Handle>>;
+    let blocked: Vec<(Pid, Option<*const WaitQueue>)>;
     {
         let mut processes = PROCESSES.lock();
-        let len = processes.len();
-        let curr_index = CURR_INDEX.lock();
-        processes.remove(prev_index(*curr_index, len));
-        curr = processes[*curr_index].ctx.as_ptr();
+        let mut curr_index = CURR_INDEX.lock();
+        let pid = processes[*curr_index].pid;
+        let group = processes[*curr_index].group;
+
+        // The other threads were running the old program. None of them is running right now, so they can just go away
+        // (once we let go of PROCESSES, whatever they were using can be dropped)
+        in_use = processes.iter_mut()
+            .filter(|p| p.group == group && p.pid != pid)
+            .filter_map(|p| p.in_use.take())
+            .collect();
+        blocked = processes.iter_mut()
+            .filter(|p| p.group == group && p.pid != pid)
+            .map(|p| (p.pid, p.blocked_on.take()))
+            .collect();
+        remove_where(&mut processes, &mut curr_index, |p| p.group == group && p.pid != pid && p.pid != group);
+        if pid != group {
+            let i = processes.iter().position(|p| p.pid == group).unwrap();
+            let parent = processes[i].parent;
+            remove_where(&mut processes, &mut curr_index, |p| p.pid == group);
+            let curr = &mut processes[*curr_index];
+            curr.pid = group;
+            curr.parent = parent;
+        }
+        processes[*curr_index].privileged = false;
+
+        let curr = processes[*curr_index].ctx.as_mut().unwrap();
+        old_dir = curr.dir;
+        // We're on this very stack, but only ever below the frame of the syscall we're in, which is where the new frame goes
+        curr.esp = unsafe { curr.stack.push_frame(frame) };
+        curr.dir = dir;
+        ctx = curr;
+    }
+
+    forget_blocked(&blocked);
+    drop(in_use);
+    unsafe {
+        forget_futexes(old_dir);
+        crate::vma::free_areas(old_dir);
+        drop(Box::from_raw(old_dir));
+        enter_context(ctx);
+    }
+}
+
+/// Ends the currently running process because it can't go on (e.g. it caused a page fault).
+pub(crate) fn kill_process() -> ! {
+    exit(KILLED_EXIT_STATUS);
+}
+
+/// Threads blocked in wait_pid, by the process whose children they're waiting for. Woken whenever one of its children exits
+static CHILD_WAITERS: Mutex<BTreeMap<Pid, Vec<Pid>>> = Mutex::new(BTreeMap::new());
+
+/// Waits until the current process' child pid exits, and collects its exit status.
+/// Must be called with interrupts disabled, like block_current
+pub(crate) fn wait_pid(pid: Pid, status: &mut i32) -> Result<(), Errno> {
+    loop {
+        {
+            let mut processes = PROCESSES.lock();
+            let mut curr_index = CURR_INDEX.lock();
+            let curr_pid = processes[*curr_index].group;
+
+            let Some(i) = processes.iter().position(|p| p.pid == pid && p.parent == curr_pid) else {
+                return Err(Errno::ECHILD);
+            };
+            if has_ended(&processes, pid) {
+                *status = processes[i].exit_status;
+                processes.remove(i);
+                if i < *curr_index {
+                    *curr_index -= 1;
+                }
+                return Ok(());
+            }
+            CHILD_WAITERS.lock().entry(curr_pid).or_default().push(processes[*curr_index].pid);
+        }
+        // Some child exited, but not necessarily this one (or another thread collected it first), so check again
+        block_current();
     }
-    next_program(curr);
+}
+
+/// Set once the first process has been entered. Until then, the kernel itself is the one running
+static mut HAS_LOADED_PROCESSES: bool = false;
+
+/// What a new thread starts out with, besides its frame. Mostly copied from whoever creates it
+struct Inherited {
+    fds: Arc<Mutex<FdTable>>,
+    privileged: bool,
+    traced: bool,
+    cwd: Inode,
+}
+
+impl Inherited {
+    /// The current thread's (sharing its descriptors)
+    fn current() -> Self {
+        let processes = PROCESSES.lock();
+        let curr = &processes[*CURR_INDEX.lock()];
+        Inherited { fds: curr.fds.clone(), privileged: curr.privileged, traced: curr.traced, cwd: curr.cwd }
+    }
+
+    /// For whatever the kernel starts itself
+    fn kernel(fds: FdTable) -> Self {
+        Inherited { fds: Arc::new(Mutex::new(fds)), privileged: true, traced: false, cwd: fs::ROOT }
+    }
+}
+
+/// Adds a new thread to the scheduler, which starts by returning to frame in dir. It joins the process group,
+/// or starts a new process (as a child of parent) if group is None. Returns its PID
+fn add(frame: TrapFrame, dir: *mut PageDirectory, parent: Pid, group: Option<Pid>, inherited: Inherited) -> Pid {
+    let mut stack = KernelStack::new();
+    let esp = unsafe { stack.push_frame(frame) };
+    let context = Context { esp, syscall_frame: core::ptr::null_mut(), dir, stack };
+
+    let mut processes = PROCESSES.lock();
+    let mut next_pid = NEXT_PID.lock();
+    let pid = *next_pid;
+    *next_pid += 1;
+
+    processes.push(Process {
+        pid, parent, group: group.unwrap_or(pid), state: State::Ready, exit_status: 0, ctx: Some(context),
+        nice: 0, level: 0, slice_left: 0, times: ProcessTimes::default(),
+        fds: inherited.fds, privileged: inherited.privileged, traced: inherited.traced, cwd: inherited.cwd, in_use: None, blocked_on: None,
+    });
+    pid
+}
+
+/// Adds a new process to the scheduler, as a child of the current one. It starts by returning to frame in dir. Returns its PID.
+/// same_program is set when it runs the same program as its parent (i.e. it's a fork), so it keeps its parent's privileges
+pub(crate) fn register(frame: TrapFrame, dir: *mut PageDirectory, same_program: bool) -> Pid {
+    // If nothing is running yet, the kernel is the one creating this one, and it gets the console and keyboard (and is privileged).
+    // Otherwise it starts out with copies of its parent's descriptors
+    let (parent, inherited) = if has_loaded_processes() {
+        let mut inherited = Inherited::current();
+        let fds = inherited.fds.lock().clone();
+        inherited.fds = Arc::new(Mutex::new(fds));
+        inherited.privileged &= same_program;
+        (curr_pid(), inherited)
+    } else {
+        (0, Inherited::kernel(FdTable::standard()))
+    };
+    // The task scheduler will get to it (see start, for the ones the kernel creates)
+    add(frame, dir, parent, None, inherited)
+}
+
+/// Starts running whatever the kernel registered (processes and kernel threads), and never returns.
+/// (There's no running process for next_program to save the context of, so the first one has to be entered manually)
+pub(crate) fn start() -> ! {
+    unsafe {
+        // Until we're in, a tick has nowhere to save the kernel's frame
+        asm!("cli");
+
+        // The idle task needs the heap, so now is as early as we can create it
+        let mut stack = KernelStack::new();
+        let esp = stack.push_frame(crate::userspace::kernel_frame(idle as u32));
+        IDLE_CTX = Some(Context { esp, syscall_frame: core::ptr::null_mut(), dir: crate::paging::kernel_dir(), stack });
+
+        let ctx: *const Context = {
+            let mut processes = PROCESSES.lock();
+            let i = find_ready(&processes, 0).expect("Nothing to run");
+            run(&mut processes, i);
+            processes[i].ctx.as_ref().unwrap()
+        };
+        HAS_LOADED_PROCESSES = true;
+        enter_context(ctx);
+    }
+}
+
+/// Starts a new thread in the current process, which calls entry(arg) in user mode on stack (the top of memory the caller
+/// set aside for it). Once entry returns, the thread exits with its return value. Returns the new thread's ID.
+/// Fails with EFAULT if the caller can't write right below stack
+pub(crate) fn thread_create(entry: ThreadEntry, stack: *mut u8, arg: usize) -> Result<Pid, Errno> {
+    // entry's parameters go below stack, as if start_of_thread was called
+    let esp = (stack as usize).checked_sub(12).ok_or(Errno::EFAULT)?;
+    crate::uaccess::check_range(esp, 12, true)?;
+    unsafe {
+        let esp = esp as *mut u32;
+        *esp = 0; // the return address. start_of_thread never returns
+        *esp.add(1) = entry as usize as u32;
+        *esp.add(2) = arg as u32;
+        let frame = crate::userspace::user_frame(start_of_thread as u32, esp as u32);
+
+        // Threads aren't anyone's children, so only thread_join can collect their status
+        Ok(add(frame, PageDirectory::curr(), 0, Some(curr_pid()), Inherited::current()))
+    }
+}
+
+extern "C" {
+    /// Runs in user mode, like the thread itself. Takes entry and arg
+    fn start_of_thread();
+}
+
+// Lives in .user_text, like start_of_program_execution. It calls entry(arg), and exits the thread with whatever that returns
+core::arch::global_asm!(
+    ".pushsection .user_text, \"ax\"",
+    ".global start_of_thread",
+    "start_of_thread:",
+    "mov eax, [esp + 4]", // entry
+    "push dword ptr [esp + 8]", // arg
+    "call eax",
+    "mov ebx, eax",
+    "mov eax, {thread_exit}",
+    "int 0x80",
+    "2:",
+    "jmp 2b",
+    ".popsection",
+    thread_exit = const crate::syscall::Syscall::ThreadExit as u32,
+);
+
+/// Woken whenever a thread exits, so that thread_join can check whether it was the one it's waiting for
+static THREAD_EXITED: WaitQueue = WaitQueue::new();
+
+/// Waits until the thread tid of the current process exits, and collects its exit status into status. Fails with ESRCH if
+/// there's no such thread. (A process' first thread can't be joined, since its parent is the one that collects its status)
+pub(crate) fn thread_join(tid: Pid, status: &mut i32) -> Result<(), Errno> {
+    loop {
+        {
+            let mut processes = PROCESSES.lock();
+            let mut curr_index = CURR_INDEX.lock();
+            let curr_tid = processes[*curr_index].pid;
+            let group = processes[*curr_index].group;
+
+            let Some(i) = processes.iter().position(|p| p.pid == tid && p.group == group && p.pid != group && p.pid != curr_tid) else {
+                return Err(Errno::ESRCH);
+            };
+            if processes[i].state == State::Zombie {
+                *status = processes[i].exit_status;
+                remove_where(&mut processes, &mut curr_index, |p| p.pid == tid);
+                return Ok(());
+            }
+        }
+        THREAD_EXITED.wait();
+    }
+}
+
+/// Starts a thread that runs f in ring 0, in the kernel's directory. It exits once f returns.
+/// Meant for deferred work. If nothing is running yet, it starts along with everything else (see start).
+/// fds are its descriptors, which whatever it runs gets copies of
+pub(crate) fn spawn_kernel_thread(f: fn(), fds: FdTable) -> Pid {
+    let mut frame = crate::userspace::kernel_frame(kernel_thread_entry as u32);
+    frame.eax = f as usize as u32; // see kernel_thread_entry
+    add(frame, crate::paging::kernel_dir(), 0, None, Inherited::kernel(fds))
+}
+
+/// Where kernel threads start. The function to run is in eax
+#[naked]
+extern "C" fn kernel_thread_entry() {
+    unsafe {
+        asm!(
+            "push eax",
+            "call kernel_thread_main",
+            options(noreturn)
+        )
+    }
+}
+
+#[no_mangle]
+#[allow(improper_ctypes_definitions)] // only ever called by kernel_thread_entry
+extern "C" fn kernel_thread_main(f: fn()) -> ! {
+    f();
+    // Kernel threads run with interrupts enabled, but exiting needs them disabled
+    unsafe { asm!("cli"); }
+    thread_exit(0);
+}
+
+/// Creates a copy of the current process (which must have called this via the Fork syscall), as its child.
+/// Both return from the syscall: the parent gets the child's PID (the return value), and the child gets 0.
+pub(crate) fn fork() -> Pid {
+    // The child starts by returning from this very syscall
+    let mut frame: TrapFrame = unsafe { *crate::syscall::curr_frame() };
+    assert!(frame.from_user(), "Only user programs can fork");
+    frame.eax = 0;
+
+    unsafe {
+        let parent_dir = PageDirectory::curr();
+        let child_dir = (*parent_dir).fork();
+        crate::vma::clone_areas(parent_dir, child_dir);
+        register(frame, child_dir, true)
+    }
+}
+
+/// Sets the nice value of pid, which has to be either a thread of the current process or one of its children.
+/// Values outside of MIN_NICE..=MAX_NICE are clamped. Fails with ESRCH if there's no such process.
+/// Anyone can be made nicer, but only privileged processes may make anyone less nice (or it's EPERM)
+pub(crate) fn set_nice(pid: Pid, nice: i32) -> Result<(), Errno> {
+    let mut processes = PROCESSES.lock();
+    let curr = &processes[*CURR_INDEX.lock()];
+    let (curr_pid, privileged) = (curr.group, curr.privileged);
+    match processes.iter_mut().find(|p| p.pid == pid && (p.group == curr_pid || p.parent == curr_pid)) {
+        Some(p) => {
+            let nice = nice.clamp(MIN_NICE, MAX_NICE);
+            if nice < p.nice && !privileged {
+                return Err(Errno::EPERM);
+            }
+            p.nice = nice;
+            Ok(())
+        }
+        None => Err(Errno::ESRCH),
+    }
+}
+
+/// Turns syscall tracing on or off for pid, which has to be either the current process or one of its children.
+/// It applies to all of its threads, and to whatever processes it creates from then on. Fails with ESRCH if there's no such process
+pub(crate) fn set_trace(pid: Pid, enabled: bool) -> Result<(), Errno> {
+    let mut processes = PROCESSES.lock();
+    let curr_pid = processes[*CURR_INDEX.lock()].group;
+    if !processes.iter().any(|p| p.pid == pid && p.group == pid && (pid == curr_pid || p.parent == curr_pid)) {
+        return Err(Errno::ESRCH);
+    }
+    for p in processes.iter_mut().filter(|p| p.group == pid) {
+        p.traced = enabled;
+    }
+    Ok(())
+}
+
+/// Sets the length (in ticks) of a slice at the first feedback level
+pub(crate) fn set_quantum(ticks: u32) {
+    unsafe { QUANTUM = u32::max(ticks, 1); }
+}
+
+/// Writes how long pid has been running for into out. Fails with ESRCH if there's no such process
+pub(crate) fn get_times(pid: Pid, out: &mut ProcessTimes) -> Result<(), Errno> {
+    *out = PROCESSES.lock().iter().find(|p| p.pid == pid).ok_or(Errno::ESRCH)?.times;
+    Ok(())
 }
 
 pub fn has_loaded_processes() -> bool { unsafe { HAS_LOADED_PROCESSES } }
 
-pub fn get_curr_process() -> Process { PROCESSES.lock()[*CURR_INDEX.lock()] }
+/// Returns the PID of the running process
+pub fn curr_pid() -> Pid { PROCESSES.lock()[*CURR_INDEX.lock()].group }
+
+/// Returns the descriptor table of the running process
+pub fn curr_fds() -> Arc<Mutex<FdTable>> { PROCESSES.lock()[*CURR_INDEX.lock()].fds.clone() }
+
+/// Whether the running process may make privileged syscalls
+pub fn is_privileged() -> bool { PROCESSES.lock()[*CURR_INDEX.lock()].privileged }
+
+/// Returns the running process' working directory
+pub fn curr_cwd() -> Inode { PROCESSES.lock()[*CURR_INDEX.lock()].cwd }
+
+/// Changes the running process' working directory (for all of its threads)
+pub(crate) fn set_cwd(dir: Inode) {
+    let mut processes = PROCESSES.lock();
+    let group = processes[*CURR_INDEX.lock()].group;
+    for p in processes.iter_mut().filter(|p| p.group == group) {
+        p.cwd = dir;
+    }
+}
+
+/// Keeps handle in the current thread until take_in_use (see fd::with_handle), and returns where it is
+pub(crate) fn set_in_use(handle: Handle) -> *const Handle {
+    let handle = Box::new(handle);
+    let ptr: *const Handle = &*handle;
+    PROCESSES.lock()[*CURR_INDEX.lock()].in_use = Some(handle);
+    ptr
+}
+
+/// Takes back what set_in_use kept. Dropping it might wake someone up, so it's only dropped by the caller
+pub(crate) fn take_in_use() -> Option<Box<Handle>> {
+    PROCESSES.lock()[*CURR_INDEX.lock()].in_use.take()
+}
+
+/// Whether dir is the working directory of any process
+pub(crate) fn is_anyones_cwd(dir: Inode) -> bool { PROCESSES.lock().iter().any(|p| p.cwd == dir) }
+
+/// Whether the running process' syscalls are being traced
+pub fn is_traced() -> bool { PROCESSES.lock()[*CURR_INDEX.lock()].traced }
+
+/// Returns the ID of the running thread
+pub fn curr_tid() -> Pid { PROCESSES.lock()[*CURR_INDEX.lock()].pid }
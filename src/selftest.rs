@@ -0,0 +1,69 @@
+//! Checks the kernel runs at boot when it's built with the selftest feature, before the shell starts.
+//! They run as a kernel thread (see kernel_main), so they can start programs and wait for them like anyone else.
+//! A failed check panics.
+
+use alloc::vec::Vec;
+
+use crate::{println, syscall};
+
+/// Where the test program is loaded
+const PROGRAM_ADDR: u32 = 0x0804_8000;
+
+pub(crate) fn run() {
+    frame_accounting();
+    println!("selftest: all passed");
+
+    let shell = syscall::open("/shell", false).expect("No /shell");
+    syscall::execute_file(shell).expect("/shell isn't a valid executable");
+    let _ = syscall::close(shell);
+}
+
+/// Running a program and collecting its exit status should give back every frame it took
+fn frame_accounting() {
+    let program = tiny_program();
+    // The first run might grow things that stay grown (e.g. the kernel's heap), so it doesn't count
+    run_and_reap(&program);
+
+    let before = syscall::get_mem_info().free_frames;
+    run_and_reap(&program);
+    let after = syscall::get_mem_info().free_frames;
+    assert_eq!(before, after, "selftest: {} free frames before running a program, but {} after", before, after);
+    println!("selftest: frame accounting ok ({} free frames)", after);
+}
+
+fn run_and_reap(program: &[u8]) {
+    let pid = syscall::RunProgram::call(program).expect("selftest: the test program didn't load");
+    assert_eq!(syscall::wait(pid), Ok(0), "selftest: the test program didn't exit with 0");
+}
+
+/// An ELF executable with one segment (the whole file), whose entry point is `xor eax, eax; ret`, i.e. it returns 0
+fn tiny_program() -> Vec<u8> {
+    const HEADER_SIZE: u16 = 52;
+    const PROGRAM_HEADER_SIZE: u16 = 32;
+    const CODE: [u8; 3] = [0x31, 0xC0, 0xC3];
+    let entry_offset = (HEADER_SIZE + PROGRAM_HEADER_SIZE) as u32;
+    let file_size = entry_offset + CODE.len() as u32;
+
+    let mut image = Vec::new();
+    // The ELF header: a 32 bit, little endian, x86 executable
+    image.extend([0x7F, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    image.extend(2u16.to_le_bytes()); // executable
+    image.extend(3u16.to_le_bytes()); // x86
+    image.extend(1u32.to_le_bytes()); // version
+    image.extend((PROGRAM_ADDR + entry_offset).to_le_bytes());
+    image.extend((HEADER_SIZE as u32).to_le_bytes()); // where the program header is
+    image.extend(0u32.to_le_bytes()); // no section header
+    image.extend(0u32.to_le_bytes()); // flags
+    image.extend(HEADER_SIZE.to_le_bytes());
+    image.extend(PROGRAM_HEADER_SIZE.to_le_bytes());
+    image.extend(1u16.to_le_bytes()); // one program header entry
+    image.extend([0u8; 6]); // no sections
+
+    // The program header: load the whole file at PROGRAM_ADDR, readable and executable
+    for word in [1, 0, PROGRAM_ADDR, PROGRAM_ADDR, file_size, file_size, 5, 0x1000] {
+        image.extend(u32::to_le_bytes(word));
+    }
+
+    image.extend(CODE);
+    image
+}
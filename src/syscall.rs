@@ -1,182 +1,410 @@
+//! The syscall ABI. Syscalls go through `int 0x80`, or through `sysenter` if the CPU has it and the caller is in ring 3
+//! (see raw_syscall). Either way:
+//! - eax holds the syscall's number (see the Syscall enum). Numbers never change once they're given out.
+//! - The arguments go in ebx, ecx, edx, esi, edi and ebp, in that order. Most take up one register,
+//!   but slices and strings take two (a pointer and then a length), and a Layout takes two (its size and then its alignment).
+//! - The result comes back in eax. Syscalls that can fail return a negative Errno instead (e.g. -9 for EBADF).
+//!   Whatever doesn't fit in a register is written through a pointer argument.
+//! - Every other register is preserved.
+//! - Pointers (and references, slices and strings) have to point to memory the caller may access, or the syscall fails
+//!   with EFAULT (see uaccess).
+//! - Some syscalls give direct access to the hardware or to the kernel's own state. Only privileged processes may make them
+//!   (see Syscall::is_privileged), and anyone else gets EPERM.
+//! - A few (e.g. the kernel's heap) only make sense inside the kernel, so user programs always get EPERM for them
+//!   (see Syscall::is_kernel_only).
+//! - Every syscall a traced process makes is logged to the kernel log (see process::set_trace and klog).
+
 use core::arch::asm;
+use core::alloc::Layout;
+use core::fmt;
 use alloc::{vec::Vec, string::String};
 use spin::{Mutex, Lazy};
 
-use crate::{interrupts, events::Event};
-// No variadic generics :(
-pub trait SyscallBase {}
+use crate::{interrupts, events::Event, errno::Errno, process::{Pid, ProcessTimes, ThreadEntry}, fd::Fd, uaccess};
+
+/// Something that can be passed to a syscall, in one or more registers
+pub trait SyscallArg: Sized {
+    /// How many registers it takes up
+    const WORDS: usize;
+    fn to_words(self, words: &mut [u32]);
+    /// Makes sure words can safely be passed to from_words, e.g. that a reference points to memory the caller may access
+    fn check(_words: &[u32]) -> Result<(), Errno> { Ok(()) }
+    /// Rebuilds whatever to_words was called with. Only called once check passes
+    unsafe fn from_words(words: &[u32]) -> Self;
+    /// Writes the argument the way a syscall trace shows it. By default, that's just the raw words
+    fn trace(words: &[u32], f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, word) in words.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{:#x}", word)?;
+        }
+        Ok(())
+    }
+}
 
-pub trait Syscall0: SyscallBase {}
+/// Makes sure a pointer to count Ts at addr is aligned, and that the caller may access them
+fn check_ptr<T>(addr: u32, count: u32, write: bool) -> Result<(), Errno> {
+    let addr = addr as usize;
+    if addr == 0 || addr % core::mem::align_of::<T>() != 0 {
+        return Err(Errno::EFAULT);
+    }
+    let len = (count as usize).checked_mul(core::mem::size_of::<T>()).ok_or(Errno::EFAULT)?;
+    uaccess::check_range(addr, len, write)
+}
 
-pub trait Syscall1: SyscallBase {
-    type T1;
+/// What a syscall returns, as it's encoded in eax
+pub trait SyscallRet {
+    /// What the caller ends up with
+    type User;
+    fn into_eax(self) -> i32;
+    fn from_eax(eax: i32) -> Self::User;
+    /// Writes the result the way a syscall trace shows it
+    fn trace(eax: i32, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", eax) }
 }
 
-pub trait Syscall2: SyscallBase {
-    type T1;
-    type T2;
+/// How many characters of a string argument a syscall trace shows
+const TRACED_STR_LEN: usize = 32;
+
+/// Displays whatever its function writes, so that syscall traces can be formatted straight into the log
+struct Trace<F: Fn(&mut fmt::Formatter) -> fmt::Result>(F);
+
+impl<F: Fn(&mut fmt::Formatter) -> fmt::Result> fmt::Display for Trace<F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { (self.0)(f) }
 }
 
-pub trait Syscall3: SyscallBase {
-    type T1;
-    type T2;
-    type T3;
+macro_rules! impl_word_arg {
+    ($($type:ty),*) => {
+        $(
+            impl SyscallArg for $type {
+                const WORDS: usize = 1;
+                fn to_words(self, words: &mut [u32]) { words[0] = self as u32; }
+                unsafe fn from_words(words: &[u32]) -> Self { words[0] as $type }
+                fn trace(words: &[u32], f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", words[0] as $type) }
+            }
+
+            impl SyscallRet for $type {
+                type User = $type;
+                fn into_eax(self) -> i32 { self as i32 }
+                fn from_eax(eax: i32) -> Self { eax as $type }
+            }
+        )*
+    };
 }
 
-pub trait Syscall4: SyscallBase {
-    type T1;
-    type T2;
-    type T3;
-    type T4;
+impl_word_arg!(u8, u16, u32, i32, usize);
+
+impl SyscallArg for bool {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { words[0] = self as u32; }
+    unsafe fn from_words(words: &[u32]) -> Self { words[0] != 0 }
+    fn trace(words: &[u32], f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}", words[0] != 0) }
 }
 
-#[macro_export]
-macro_rules! decl_syscall {
-    (_internal $name: ident = $func: path [$($arg_name:ident : $arg_type:ty),*]) => {
-        pub struct $name {
-            $($arg_name: $arg_type),*
-        }
+impl<T> SyscallArg for *const T {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { words[0] = self as usize as u32; }
+    unsafe fn from_words(words: &[u32]) -> Self { words[0] as usize as *const T }
+}
 
-        impl $name {
-            pub fn call($($arg_name: $arg_type),*) {
-                // this should keep existing until the end of the function (after the syscall)
-                let s = $name { $($arg_name),* };
-                unsafe {
-                    asm!("int 0x80", in("eax") &s, in("ebx") Syscall::$name as u32);
-                }
-            }
+impl<T> SyscallArg for *mut T {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { words[0] = self as usize as u32; }
+    unsafe fn from_words(words: &[u32]) -> Self { words[0] as usize as *mut T }
+}
 
-            // unsafe in case $func is unsafe.
-            #[allow(unused_unsafe)]
-            fn call_internal(&self) { unsafe { $func($(self.$arg_name),*); } }
-        }
+impl<'a, T> SyscallArg for &'a T {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { (self as *const T).to_words(words); }
+    fn check(words: &[u32]) -> Result<(), Errno> { check_ptr::<T>(words[0], 1, false) }
+    unsafe fn from_words(words: &[u32]) -> Self { &*<*const T>::from_words(words) }
+}
 
-        impl SyscallBase for $name {}
-    };
+impl<'a, T> SyscallArg for &'a mut T {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { (self as *mut T).to_words(words); }
+    fn check(words: &[u32]) -> Result<(), Errno> { check_ptr::<T>(words[0], 1, true) }
+    unsafe fn from_words(words: &[u32]) -> Self { &mut *<*mut T>::from_words(words) }
+}
 
-    (_internal $name: ident<'a> = $func: path [$($arg_name:ident : $arg_type:ty),*]) => {
-        pub struct $name<'a> {
-            $($arg_name: $arg_type),*
-        }
+impl<'a, T> SyscallArg for &'a [T] {
+    const WORDS: usize = 2;
+    fn to_words(self, words: &mut [u32]) {
+        self.as_ptr().to_words(&mut words[..1]);
+        words[1] = self.len() as u32;
+    }
+    fn check(words: &[u32]) -> Result<(), Errno> { check_ptr::<T>(words[0], words[1], false) }
+    unsafe fn from_words(words: &[u32]) -> Self {
+        core::slice::from_raw_parts(<*const T>::from_words(words), words[1] as usize)
+    }
+    fn trace(words: &[u32], f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{:#x}, {}", words[0], words[1]) }
+}
 
-        impl<'a> $name<'a> {
-            pub fn call($($arg_name: $arg_type),*) {
-                // this should keep existing until the end of the function (after the syscall)
-                let s = $name { $($arg_name),* };
-                unsafe {
-                    asm!("int 0x81", in("eax") &s, in("ebx") SyscallLifetime::$name as u32);
-                }
-            }
+impl<'a, T> SyscallArg for &'a mut [T] {
+    const WORDS: usize = 2;
+    fn to_words(self, words: &mut [u32]) {
+        words[1] = self.len() as u32;
+        self.as_mut_ptr().to_words(&mut words[..1]);
+    }
+    fn check(words: &[u32]) -> Result<(), Errno> { check_ptr::<T>(words[0], words[1], true) }
+    unsafe fn from_words(words: &[u32]) -> Self {
+        core::slice::from_raw_parts_mut(<*mut T>::from_words(words), words[1] as usize)
+    }
+    fn trace(words: &[u32], f: &mut fmt::Formatter) -> fmt::Result { <&[T]>::trace(words, f) }
+}
 
-            // unsafe in case $func is unsafe.
-            #[allow(unused_unsafe)]
-            fn call_internal(&mut self) { unsafe { $func($(self.$arg_name),*); } }
+impl<'a> SyscallArg for &'a str {
+    const WORDS: usize = 2;
+    fn to_words(self, words: &mut [u32]) { self.as_bytes().to_words(words); }
+    fn check(words: &[u32]) -> Result<(), Errno> {
+        <&[u8]>::check(words)?;
+        core::str::from_utf8(unsafe { <&[u8]>::from_words(words) }).map_err(|_| Errno::EINVAL)?;
+        Ok(())
+    }
+    unsafe fn from_words(words: &[u32]) -> Self { core::str::from_utf8_unchecked(<&[u8]>::from_words(words)) }
+    fn trace(words: &[u32], f: &mut fmt::Formatter) -> fmt::Result {
+        // It might not even be the caller's to read, in which case all we can show is where it is
+        if Self::check(words).is_err() {
+            return <&[u8]>::trace(words, f);
         }
+        let s = unsafe { Self::from_words(words) };
+        let end = s.char_indices().nth(TRACED_STR_LEN).map_or(s.len(), |(i, _)| i);
+        write!(f, "{:?}{}", &s[..end], if end < s.len() { "..." } else { "" })
+    }
+}
 
-        impl<'a> SyscallBase for $name<'a> {}
-    };
+impl SyscallArg for Layout {
+    const WORDS: usize = 2;
+    fn to_words(self, words: &mut [u32]) {
+        words[0] = self.size() as u32;
+        words[1] = self.align() as u32;
+    }
+    fn check(words: &[u32]) -> Result<(), Errno> {
+        Layout::from_size_align(words[0] as usize, words[1] as usize).map_err(|_| Errno::EINVAL)?;
+        Ok(())
+    }
+    unsafe fn from_words(words: &[u32]) -> Self {
+        Layout::from_size_align_unchecked(words[0] as usize, words[1] as usize)
+    }
+}
 
-    ($name: ident = $func: path {}) => {
-        decl_syscall!(_internal $name = $func[]);
-        impl Syscall0 for $name {}
-    };
-    ($name: ident = $func: path {$arg1n: ident: $arg1t: ty}) => {
-        decl_syscall!(_internal $name = $func[$arg1n: $arg1t]);
-        impl Syscall1 for $name {
-            type T1 = $arg1t;
-        }
-    };
-    ($name: ident = $func: path {$arg1n: ident: $arg1t: ty, $arg2n: ident: $arg2t: ty}) => {
-        decl_syscall!(_internal $name = $func[$arg1n: $arg1t, $arg2n: $arg2t]);
-        impl Syscall2 for $name {
-            type T1 = $arg1t;
-            type T2 = $arg2t;
-        }
-    };
-    ($name: ident = $func: path {$arg1n: ident: $arg1t: ty, $arg2n: ident: $arg2t: ty, $arg3n: ident: $arg3t: ty}) => {
-        decl_syscall!(_internal $name = $func[$arg1n: $arg1t, $arg2n: $arg2t, $arg3n: $arg3t]);
-        impl Syscall3 for $name {
-            type T1 = $arg1t;
-            type T2 = $arg2t;
-            type T3 = $arg3t;
-        }
-    };
-    ($name: ident = $func: path {$arg1n: ident: $arg1t: ty, $arg2n: ident: $arg2t: ty, $arg3n: ident: $arg3t: ty, $arg4n: ident: $arg4t: ty}) => {
-        decl_syscall!(_internal $name = $func[$arg1n: $arg1t, $arg2n: $arg2t, $arg3n: $arg3t, $arg4n: $arg4t]);
-        impl Syscall4 for $name {
-            type T1 = $arg1t;
-            type T2 = $arg2t;
-            type T3 = $arg3t;
-            type T4 = $arg4t;
+impl SyscallArg for crate::keyboard::Key {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { words[0] = self as u32; }
+    /// Anything that isn't one of Key's values is EINVAL (from_u8 only knows the regular scancodes, the extended ones come after them)
+    fn check(words: &[u32]) -> Result<(), Errno> {
+        use crate::keyboard::Key;
+        match u8::try_from(words[0]) {
+            Ok(val) if Key::from_u8(val).is_some() || (Key::MMPrevious as u8..=Key::Unknown as u8).contains(&val) => Ok(()),
+            _ => Err(Errno::EINVAL),
         }
-    };
+    }
+    unsafe fn from_words(words: &[u32]) -> Self { core::mem::transmute(words[0] as u8) }
+}
 
-    ($name: ident<'a> = $func: path {}) => {
-        decl_syscall!(_internal $name<'a> = $func[]);
-        impl<'a> Syscall0 for $name<'a> {}
-    };
-    ($name: ident<'a> = $func: path {$arg1n: ident: $arg1t: ty}) => {
-        decl_syscall!(_internal $name<'a> = $func[$arg1n: $arg1t]);
-        impl<'a> Syscall1 for $name<'a> {
-            type T1 = $arg1t;
-        }
-    };
-    ($name: ident<'a> = $func: path {$arg1n: ident: $arg1t: ty, $arg2n: ident: $arg2t: ty}) => {
-        decl_syscall!(_internal $name<'a> = $func[$arg1n: $arg1t, $arg2n: $arg2t]);
-        impl<'a> Syscall2 for $name<'a> {
-            type T1 = $arg1t;
-            type T2 = $arg2t;
-        }
-    };
-    ($name: ident<'a> = $func: path {$arg1n: ident: $arg1t: ty, $arg2n: ident: $arg2t: ty, $arg3n: ident: $arg3t: ty}) => {
-        decl_syscall!(_internal $name<'a> = $func[$arg1n: $arg1t, $arg2n: $arg2t, $arg3n: $arg3t]);
-        impl<'a> Syscall3 for $name<'a> {
-            type T1 = $arg1t;
-            type T2 = $arg2t;
-            type T3 = $arg3t;
-        }
-    };
-    ($name: ident<'a> = $func: path {$arg1n: ident: $arg1t: ty, $arg2n: ident: $arg2t: ty, $arg3n: ident: $arg3t: ty, $arg4n: ident: $arg4t: ty}) => {
-        decl_syscall!(_internal $name<'a> = $func[$arg1n: $arg1t, $arg2n: $arg2t, $arg3n: $arg3t, $arg4n: $arg4t]);
-        impl<'a> Syscall4 for $name<'a> {
-            type T1 = $arg1t;
-            type T2 = $arg2t;
-            type T3 = $arg3t;
-            type T4 = $arg4t;
-        }
-    };
+impl SyscallArg for ThreadEntry {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { words[0] = self as usize as u32; }
+    unsafe fn from_words(words: &[u32]) -> Self { core::mem::transmute(words[0] as usize) }
 }
 
-macro_rules! decl_syscalls {
-    ( $( $name:ident = $syscall:path { $( $param:ident : $ty:ty ),* } ),* ) => {
-        $(decl_syscall!($name = $syscall { $( $param : $ty ),* });)*
+impl SyscallArg for extern "x86-interrupt" fn() {
+    const WORDS: usize = 1;
+    fn to_words(self, words: &mut [u32]) { words[0] = self as usize as u32; }
+    unsafe fn from_words(words: &[u32]) -> Self { core::mem::transmute(words[0] as usize) }
+}
 
-        #[repr(u32)]
-        pub enum Syscall {
-            $( $name, )*
-        }
+impl SyscallRet for () {
+    type User = ();
+    fn into_eax(self) -> i32 { 0 }
+    fn from_eax(_: i32) {}
+}
 
-        #[no_mangle]
-        extern "C" fn syscall_handler_inner(syscall: u32, ty: Syscall) {
-            match ty {
-                $( Syscall::$name => unsafe{core::mem::transmute::<u32, &$name>(syscall)}.call_internal(), )*
-            }
+impl SyscallRet for bool {
+    type User = bool;
+    fn into_eax(self) -> i32 { self as i32 }
+    fn from_eax(eax: i32) -> bool { eax != 0 }
+}
+
+impl<T> SyscallRet for *mut T {
+    type User = *mut T;
+    fn into_eax(self) -> i32 { self as usize as i32 }
+    fn from_eax(eax: i32) -> *mut T { eax as u32 as usize as *mut T }
+    fn trace(eax: i32, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{:#x}", eax as u32) }
+}
+
+impl<T: 'static> SyscallRet for &'static T {
+    type User = &'static T;
+    fn into_eax(self) -> i32 { self as *const T as usize as i32 }
+    fn from_eax(eax: i32) -> &'static T { unsafe { &*(eax as u32 as usize as *const T) } }
+    fn trace(eax: i32, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{:#x}", eax as u32) }
+}
+
+/// Ok values have to be non-negative, so that they can't be mistaken for an Errno
+impl<T: SyscallRet, E: Into<Errno>> SyscallRet for Result<T, E> {
+    type User = Result<T::User, Errno>;
+    fn into_eax(self) -> i32 {
+        match self {
+            Ok(value) => value.into_eax(),
+            Err(err) => -(err.into() as i32),
         }
-    };
+    }
+    fn from_eax(eax: i32) -> Self::User {
+        if eax < 0 { Err(Errno::from_code(-eax)) } else { Ok(T::from_eax(eax)) }
+    }
+    fn trace(eax: i32, f: &mut fmt::Formatter) -> fmt::Result {
+        if eax < 0 { write!(f, "{} {:?}", eax, Errno::from_code(-eax)) } else { T::trace(eax, f) }
+    }
+}
 
-    ( $( $name:ident<'a> = $syscall:path { $( $param:ident : $ty:ty ),* } ),* ) => {
-        $(decl_syscall!($name<'a> = $syscall { $( $param : $ty ),* });)*
+/// Does syscall number with the arguments in words, and returns whatever it left in eax
+#[inline(always)]
+pub unsafe fn raw_syscall(number: u32, words: &[u32; 6]) -> i32 {
+    // sysexit always returns to ring 3, so the kernel itself has to use the interrupt. sysenter also loses IF, so it's
+    // only used with interrupts enabled (see sysenter_handler_inner)
+    if crate::userspace::in_user_mode() && crate::userspace::has_sysenter() && interrupts::if_set() {
+        return raw_sysenter(number, words);
+    }
+    let ret: i32;
+    asm!(
+        // esi and ebp can't be asm operands, so we load (and restore) them ourselves
+        "push ebp",
+        "push esi",
+        "mov esi, [edi + 12]",
+        "mov ebp, [edi + 20]",
+        "mov edi, [edi + 16]",
+        "int 0x80",
+        "pop esi",
+        "pop ebp",
+        inout("eax") number => ret,
+        in("ebx") words[0],
+        in("ecx") words[1],
+        in("edx") words[2],
+        inout("edi") words.as_ptr() => _,
+    );
+    ret
+}
 
+/// Like raw_syscall, but through sysenter, which skips the IDT and is a lot faster. Only for ring 3, and only if the CPU has it
+#[inline(always)]
+unsafe fn raw_sysenter(number: u32, words: &[u32; 6]) -> i32 {
+    let ret: i32;
+    asm!(
+        "push ebp",
+        "push esi",
+        // sysenter needs ecx and edx for our stack and return address, so those arguments (and ebp's) go on the stack,
+        // where sysenter_handler can find them
+        "push dword ptr [edi + 20]",
+        "push edx",
+        "push ecx",
+        "mov esi, [edi + 12]",
+        "mov edi, [edi + 16]",
+        "mov ecx, esp",
+        "lea edx, [2f]",
+        "sysenter",
+        "2:",
+        "add esp, 12",
+        "pop esi",
+        "pop ebp",
+        inout("eax") number => ret,
+        in("ebx") words[0],
+        inout("ecx") words[1] => _,
+        inout("edx") words[2] => _,
+        inout("edi") words.as_ptr() => _,
+    );
+    ret
+}
+
+/// The type a syscall returns, which is () unless it says otherwise
+macro_rules! ret_type {
+    () => { () };
+    ($ret: ty) => { $ret };
+}
+
+macro_rules! decl_syscalls {
+    ( $( $name:ident = $number:literal => $func:path { $( $param:ident : $ty:ty ),* } $( -> $ret:ty )? ),* $(,)? ) => {
+        $(
+            pub struct $name;
+
+            impl $name {
+                #[allow(unused_mut, unused_variables, unused_assignments)]
+                pub fn call<'a>($( $param: $ty ),*) -> <ret_type!($($ret)?) as SyscallRet>::User {
+                    let mut words = [0u32; 6];
+                    let mut i = 0;
+                    $(
+                        // Panics if the arguments don't fit in the registers
+                        $param.to_words(&mut words[i..i + <$ty as SyscallArg>::WORDS]);
+                        i += <$ty as SyscallArg>::WORDS;
+                    )*
+                    let eax = unsafe { raw_syscall(Syscall::$name as u32, &words) };
+                    <ret_type!($($ret)?) as SyscallRet>::from_eax(eax)
+                }
+            }
+        )*
+
+        /// Every syscall, by number. (Giving two of them the same number doesn't compile)
         #[repr(u32)]
-        pub enum SyscallLifetime {
-            $( $name, )*
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+        pub enum Syscall {
+            $( $name = $number, )*
         }
 
         #[no_mangle]
-        extern "C" fn syscall_handler_inner_lifetime<'a>(syscall: u32, ty: SyscallLifetime) {
-            match ty {
-                $( SyscallLifetime::$name => unsafe{core::mem::transmute::<u32, &mut $name<'a>>(syscall)}.call_internal(), )*
-            }
+        #[allow(unused_mut, unused_variables, unused_assignments, unused_unsafe, unreachable_code)] // unreachable_code: Exit and friends never return
+        extern "C" fn syscall_handler_inner<'a>(frame: &mut interrupts::TrapFrame) {
+            let prev = unsafe { core::mem::replace(&mut CURR_FRAME, frame) };
+            let words = [frame.ebx, frame.ecx, frame.edx, frame.esi, frame.edi, frame.ebp];
+            let eax = match frame.eax {
+                $(
+                    $number => {
+                        let traced = from_user() && crate::process::is_traced();
+                        let args = Trace(|f: &mut fmt::Formatter| {
+                            let mut i = 0;
+                            $(
+                                if i > 0 {
+                                    f.write_str(", ")?;
+                                }
+                                <$ty as SyscallArg>::trace(&words[i..i + <$ty as SyscallArg>::WORDS], f)?;
+                                i += <$ty as SyscallArg>::WORDS;
+                            )*
+                            Ok(())
+                        });
+                        let start = crate::timer::get_ticks();
+
+                        let call = || -> Result<i32, Errno> {
+                            if from_user() && (Syscall::$name.is_kernel_only()
+                                || Syscall::$name.is_privileged() && !crate::process::is_privileged()) {
+                                return Err(Errno::EPERM);
+                            }
+                            let mut i = 0;
+                            $(
+                                let arg_words = &words[i..i + <$ty as SyscallArg>::WORDS];
+                                <$ty as SyscallArg>::check(arg_words)?;
+                                let $param = unsafe { <$ty as SyscallArg>::from_words(arg_words) };
+                                i += <$ty as SyscallArg>::WORDS;
+                            )*
+                            if traced && Syscall::$name.may_not_return() {
+                                log_trace(Syscall::$name, &args, None::<(&str, u64)>);
+                            }
+                            let ret: ret_type!($($ret)?) = unsafe { $func($($param),*) };
+                            Ok(ret.into_eax())
+                        };
+                        let result = call();
+
+                        if traced {
+                            let ret = Trace(|f: &mut fmt::Formatter| match result {
+                                Ok(eax) => <ret_type!($($ret)?) as SyscallRet>::trace(eax, f),
+                                Err(err) => write!(f, "{} {:?}", -(err as i32), err),
+                            });
+                            log_trace(Syscall::$name, &args, Some((ret, crate::timer::get_ticks() - start)));
+                        }
+                        result.unwrap_or_else(|err| -(err as i32))
+                    }
+                )*
+                _ => -(Errno::ENOSYS as i32),
+            };
+            frame.eax = eax as u32;
+            unsafe { CURR_FRAME = prev; }
         }
     };
 }
@@ -186,120 +414,441 @@ pub fn init() {
         interrupts::IDT[0x80] = interrupts::Handler::new_raw(
             syscall_handler as *const () as u32, interrupts::GateType::DInterrupt, 3
         );
-        interrupts::IDT[0x81] = interrupts::Handler::new_raw(
-            syscall_handler_lifetime as *const () as u32, interrupts::GateType::DInterrupt, 3
-        );
+        if crate::userspace::has_sysenter() {
+            crate::userspace::init_sysenter(sysenter_handler as *const () as u32);
+        }
     }
 }
 
+// The entry stub saves the caller's entire state as a TrapFrame and passes it to the handler.
+// Interrupt gates already disable interrupts, and iretd restores the caller's EFLAGS (and so whether they were enabled).
+#[naked]
 extern "x86-interrupt" fn syscall_handler() {
     unsafe {
         asm!(
-            "cli",
-            "push ebx",
-            "push eax",
+            "pushad",
+            "push ds",
+            "push es",
+            "push fs",
+            "push gs",
+            "push esp", // the TrapFrame we just pushed
             "call syscall_handler_inner",
-            "add esp, 8", // pop the parameters
-            "sti"
+            "add esp, 4", // pop the parameter
+            "pop gs",
+            "pop fs",
+            "pop es",
+            "pop ds",
+            "popad", // eax is the result
+            "iretd",
+            options(noreturn)
         )
     }
 }
 
-extern "x86-interrupt" fn syscall_handler_lifetime() {
+// sysenter puts us on the current process' kernel stack with interrupts disabled, but doesn't save anything. The caller
+// passes its stack in ecx and where to return to in edx (see raw_sysenter), so we push the same TrapFrame as
+// syscall_handler does, and sysenter_handler_inner fills in whatever else an interrupt would have.
+#[naked]
+extern "C" fn sysenter_handler() {
     unsafe {
         asm!(
-            "cli",
-            "push ebx",
-            "push eax",
-            "call syscall_handler_inner_lifetime",
-            "add esp, 8", // pop the parameters
-            "sti"
+            "push 0", // user_ss
+            "push ecx", // user_esp
+            "pushfd",
+            "push 0", // cs
+            "push edx", // eip
+            "pushad",
+            "push ds",
+            "push es",
+            "push fs",
+            "push gs",
+            "push esp", // the TrapFrame we just pushed
+            "call sysenter_handler_inner",
+            "add esp, 4", // pop the parameter
+            "pop gs",
+            "pop fs",
+            "pop es",
+            "pop ds",
+            "popad", // eax is the result
+            // sysexit returns to edx, with ecx as the stack. They only ever held arguments, so we can overwrite them
+            "mov edx, [esp]",
+            "mov ecx, [esp + 12]",
+            "add esp, 8",
+            // The caller's EFLAGS. If IF is set (it's only clear after DisableInterrupts), it's set with sti instead,
+            // which only takes effect after sysexit, so nothing can interrupt us in between
+            "test dword ptr [esp], 0x200",
+            "jz 3f",
+            "and dword ptr [esp], 0xFFFFFDFF",
+            "popfd",
+            "sti",
+            "sysexit",
+            "3:",
+            "popfd",
+            "sysexit",
+            options(noreturn)
         )
     }
 }
 
+#[no_mangle]
+extern "C" fn sysenter_handler_inner(frame: &mut interrupts::TrapFrame) {
+    let (code, data) = crate::userspace::user_segments();
+    frame.cs = code;
+    frame.user_ss = data;
+    frame.eflags |= interrupts::EFLAGS_IF; // raw_syscall only uses sysenter with interrupts enabled
+
+    // The arguments that were in ecx, edx and ebp are on the caller's stack
+    let saved = frame.user_esp as usize;
+    match uaccess::check_user(saved, 12, false) {
+        Ok(()) => {
+            let [ecx, edx, ebp] = unsafe { (saved as *const [u32; 3]).read_unaligned() };
+            (frame.ecx, frame.edx, frame.ebp) = (ecx, edx, ebp);
+            syscall_handler_inner(frame);
+        }
+        Err(err) => frame.eax = -(err as i32) as u32,
+    }
+}
+
+/// The frame of the syscall the running thread is handling. Syscalls can nest (e.g. a syscall that allocates memory), so this is
+/// restored once the inner one returns. It belongs to the running thread: the scheduler keeps every other thread's in its
+/// Context, and puts it back here when switching to it (see process::activate)
+static mut CURR_FRAME: *mut interrupts::TrapFrame = core::ptr::null_mut();
+
+/// The state the caller of the current syscall will return to
+pub(crate) fn curr_frame() -> *mut interrupts::TrapFrame { unsafe { CURR_FRAME } }
+
+/// Makes frame the current syscall's, when switching to a thread that was in the middle of it
+pub(crate) fn set_curr_frame(frame: *mut interrupts::TrapFrame) { unsafe { CURR_FRAME = frame; } }
+
+/// Logs a syscall the current process made: who made it, its arguments, and what it returned along with how many ticks it took.
+/// That's None for syscalls that might never return (see Syscall::may_not_return), which are logged before they run instead
+fn log_trace(syscall: Syscall, args: impl fmt::Display, result: Option<(impl fmt::Display, u64)>) {
+    let (pid, tid) = (crate::process::curr_pid(), crate::process::curr_tid());
+    match result {
+        Some((ret, ticks)) => crate::klog::log(format_args!("[{}:{}] {:?}({}) = {} ({} ticks)\n", pid, tid, syscall, args, ret, ticks)),
+        None => crate::klog::log(format_args!("[{}:{}] {:?}({}) = ?\n", pid, tid, syscall, args)),
+    }
+}
+
+/// Whether the current syscall was made by a user program, rather than by the kernel itself
+pub(crate) fn from_user() -> bool {
+    unsafe { CURR_FRAME.as_ref() }.is_some_and(|frame| frame.cs & 3 == 3)
+}
+
 /* Definition of all specific syscalls */
 
 decl_syscalls!(
-    Print<'a> = print_syscall{args: core::fmt::Arguments<'a>},
-    AreInterruptsEnabled<'a> = are_interrupts_enabled{value: &'a mut bool},
-    Alloc<'a> = alloc{ptr: &'a mut *mut u8, layout: core::alloc::Layout},
-    RunProgram<'a> = crate::execution::run_program{program: &'a [u8]},
-    HasInitHeap<'a> = has_init_heap{out: &'a mut bool},
-    HasLoadedProcesses<'a> = has_loaded_processes{out: &'a mut bool},
-    GetCurrPageDir<'a> = get_curr_page_dir{out: &'a mut *mut crate::paging::PageDirectory},
-    GetOnKeyDown<'a> = get_on_key_down{out: &'a mut &'static Mutex<Event<crate::keyboard::KeyArgs>>},
-    GetOnKeyUp<'a> = get_on_key_up{out: &'a mut &'static Mutex<Event<crate::keyboard::KeyArgs>>},
-    GetConsole<'a> = get_console{out: &'a mut &'static Lazy<Mutex<crate::vga_console::Console>>},
-    IsKeyPressed<'a> = is_key_pressed{out: &'a mut bool, key: crate::keyboard::Key},
-    IsCapsLockActive<'a> = is_caps_lock_active{out: &'a mut bool},
-    FsGetHeader<'a> = fs_get_header{out: &'a mut &'static Lazy<Mutex<&'static mut crate::fs::Header>>},
-    GetFilesInDir<'a> = crate::fs::dir{root: &'a String, folders: &'a mut Vec<String>, files: &'a mut Vec<crate::fs::FileMetadata>},
-    ExecuteFile<'a> = crate::execution::execute_file{file: &'a mut crate::fs::File}
-);
-decl_syscalls!(
-    DisableInterrupts = crate::interrupts::disable{},
-    EnableInterrupts = crate::interrupts::enable{},
-    Halt = halt{},
-    Empty = empty{},
-    Outb = crate::io::outb{port: u16, value: u8},
-    Outw = crate::io::outw{port: u16, value: u16},
-    Outl = crate::io::outl{port: u16, value: u32},
-    ReadSectors = crate::ata::read_sectors{lba: u32, buffer: *mut u8, sector_count: usize},
-    WriteSectors = crate::ata::write_sectors{lba: u32, data: *const u8, sector_count: usize},
-    SetIsr = set_isr{index: usize, func: extern "x86-interrupt" fn(), dpl: u8},
-    PicSendEoi = crate::pic::send_eoi{irq_line: u8},
-    PicSetMask = crate::pic::set_mask{irq_line: u8, value: bool},
-    Dealloc = dealloc{ptr: *mut u8, layout: core::alloc::Layout},
-    UnreigsterProcess = crate::process::unregister_prev{},
-    IoWait = crate::io::wait{}
+    Print = 0 => print_syscall{text: &'a str},
+    AreInterruptsEnabled = 1 => crate::interrupts::is_enabled{} -> bool,
+    Alloc = 2 => alloc{layout: Layout} -> *mut u8,
+    RunProgram = 3 => run_program{program: &'a [u8]} -> Result<Pid, Errno>,
+    HasInitHeap = 4 => crate::heap::has_init{} -> bool,
+    HasLoadedProcesses = 5 => crate::process::has_loaded_processes{} -> bool,
+    GetCurrPageDir = 6 => crate::paging::PageDirectory::curr{} -> *mut crate::paging::PageDirectory,
+    GetOnKeyDown = 7 => get_on_key_down{} -> &'static Mutex<Event<crate::keyboard::KeyArgs>>,
+    GetOnKeyUp = 8 => get_on_key_up{} -> &'static Mutex<Event<crate::keyboard::KeyArgs>>,
+    GetConsole = 9 => get_console{} -> &'static Lazy<Mutex<crate::vga_console::Console>>,
+    IsKeyPressed = 10 => crate::keyboard::is_key_pressed{key: crate::keyboard::Key} -> bool,
+    IsCapsLockActive = 11 => crate::keyboard::is_caps_lock_active{} -> bool,
+    FsGetHeader = 12 => fs_get_header{} -> &'static Lazy<Mutex<&'static mut crate::fs::Header>>,
+    GetFilesInDir = 13 => files_in_dir{root: &'a str, folders: &'a mut Vec<String>, files: &'a mut Vec<crate::fs::FileMetadata>} -> Result<(), Errno>,
+    ExecuteFile = 14 => crate::fd::execute{fd: Fd} -> Result<Pid, crate::fd::IoError>,
+    GetMemInfo = 15 => mem_get_info{out: *mut crate::paging::MemInfo} -> Result<(), Errno>,
+    WaitPid = 16 => crate::process::wait_pid{pid: Pid, status: &'a mut i32} -> Result<(), Errno>,
+    GetPid = 17 => crate::process::curr_pid{} -> Pid,
+    Fork = 18 => crate::process::fork{} -> Pid,
+    Exec = 19 => exec_syscall{path: &'a str, argv: &'a [&'a str], envp: &'a [&'a str]} -> Result<(), Errno>,
+    GetTickCounts = 20 => tick_counts{out: *mut crate::timer::TickCounts} -> Result<(), Errno>,
+    SetNice = 21 => crate::process::set_nice{pid: Pid, nice: i32} -> Result<(), Errno>,
+    GetProcessTimes = 22 => crate::process::get_times{pid: Pid, out: &'a mut ProcessTimes} -> Result<(), Errno>,
+    GetTid = 23 => crate::process::curr_tid{} -> Pid,
+    ThreadCreate = 24 => crate::process::thread_create{entry: ThreadEntry, stack: *mut u8, arg: usize} -> Result<Pid, Errno>,
+    ThreadJoin = 25 => crate::process::thread_join{tid: Pid, status: &'a mut i32} -> Result<(), Errno>,
+    FutexWait = 26 => crate::process::futex_wait{addr: *const u32, expected: u32} -> Result<(), Errno>,
+    FutexWake = 27 => crate::process::futex_wake{addr: *const u32, n: usize} -> usize,
+    Pipe = 28 => crate::fd::pipe{out: &'a mut [Fd; 2]} -> Result<(), crate::fd::IoError>,
+    Read = 29 => crate::fd::read{fd: Fd, buffer: &'a mut [u8]} -> Result<usize, crate::fd::IoError>,
+    Write = 30 => crate::fd::write{fd: Fd, data: &'a [u8]} -> Result<usize, crate::fd::IoError>,
+    Close = 31 => crate::fd::close{fd: Fd} -> Result<(), crate::fd::IoError>,
+    Open = 32 => crate::fd::open{path: &'a str, create: bool} -> Result<Fd, crate::fd::IoError>,
+    Seek = 33 => crate::fd::seek{fd: Fd, pos: usize} -> Result<(), crate::fd::IoError>,
+    Dup = 34 => crate::fd::dup{fd: Fd} -> Result<Fd, crate::fd::IoError>,
+    Dup2 = 35 => crate::fd::dup2{fd: Fd, new_fd: Fd} -> Result<Fd, crate::fd::IoError>,
+    DisableInterrupts = 36 => disable_interrupts{},
+    EnableInterrupts = 37 => enable_interrupts{},
+    Halt = 38 => halt{},
+    Empty = 39 => empty{},
+    Outb = 40 => crate::io::outb{port: u16, value: u8},
+    Outw = 41 => crate::io::outw{port: u16, value: u16},
+    Outl = 42 => crate::io::outl{port: u16, value: u32},
+    ReadSectors = 43 => read_sectors{lba: u32, buffer: *mut u8, sector_count: usize} -> Result<(), Errno>,
+    WriteSectors = 44 => write_sectors{lba: u32, data: *const u8, sector_count: usize} -> Result<(), Errno>,
+    SetIsr = 45 => set_isr{index: usize, func: extern "x86-interrupt" fn(), dpl: u8},
+    PicSendEoi = 46 => crate::pic::send_eoi{irq_line: u8},
+    PicSetMask = 47 => crate::pic::set_mask{irq_line: u8, value: bool},
+    Dealloc = 48 => dealloc{ptr: *mut u8, layout: Layout},
+    Exit = 49 => crate::process::exit{status: i32},
+    IoWait = 50 => crate::io::wait{},
+    Sleep = 51 => crate::process::sleep{ms: u32},
+    Yield = 52 => crate::process::yield_now{},
+    SetQuantum = 53 => crate::process::set_quantum{ticks: u32},
+    ThreadExit = 54 => crate::process::thread_exit{status: i32},
+    SetTrace = 55 => crate::process::set_trace{pid: Pid, enabled: bool} -> Result<(), Errno>,
+    ReadLog = 56 => crate::klog::read{buffer: &'a mut [u8]} -> usize,
+    Mkdir = 57 => crate::fs::mkdir{path: &'a str} -> Result<(), crate::fs::FileError>,
+    Rmdir = 58 => crate::fs::rmdir{path: &'a str} -> Result<(), crate::fs::FileError>,
+    Readdir = 59 => crate::fs::readdir{path: &'a str, out: &'a mut [crate::fs::DirEntry]} -> Result<usize, crate::fs::FileError>,
+    Chdir = 60 => crate::fs::chdir{path: &'a str} -> Result<(), crate::fs::FileError>,
+    Getcwd = 61 => crate::fs::getcwd{out: &'a mut [u8]} -> Result<usize, crate::fs::FileError>,
 );
 
-fn print_syscall(args: core::fmt::Arguments) {
-    crate::vga_console::_print(args);
+impl Syscall {
+    /// Whether only privileged processes (and the kernel itself) may make this syscall. These are the ones that talk to the
+    /// hardware, or hand out the kernel's own structures. Everyone else goes through the file system and console syscalls
+    pub const fn is_privileged(self) -> bool {
+        matches!(
+            self,
+            Syscall::GetCurrPageDir | Syscall::GetOnKeyDown | Syscall::GetOnKeyUp | Syscall::GetConsole | Syscall::FsGetHeader
+                | Syscall::DisableInterrupts | Syscall::EnableInterrupts | Syscall::Halt | Syscall::Outb | Syscall::Outw
+                | Syscall::Outl | Syscall::ReadSectors | Syscall::WriteSectors | Syscall::SetIsr | Syscall::PicSendEoi
+                | Syscall::PicSetMask | Syscall::IoWait | Syscall::SetQuantum
+        )
+    }
+
+    /// Whether only the kernel itself may make this syscall, even privileged user programs can't. These hand out (or allocate)
+    /// kernel memory, which isn't mapped for user programs. (User programs have a heap of their own, see heap::USER_HEAP_START)
+    pub const fn is_kernel_only(self) -> bool {
+        matches!(
+            self,
+            Syscall::Alloc | Syscall::Dealloc | Syscall::GetCurrPageDir | Syscall::GetOnKeyDown | Syscall::GetOnKeyUp
+                | Syscall::GetConsole | Syscall::FsGetHeader | Syscall::GetFilesInDir
+        )
+    }
+
+    /// Whether this syscall might never return to its caller (e.g. Exit), so tracing it has to happen before it runs
+    pub const fn may_not_return(self) -> bool {
+        matches!(self, Syscall::Exit | Syscall::ThreadExit | Syscall::Exec)
+    }
+}
+
+fn print_syscall(text: &str) {
+    crate::vga_console::_print(format_args!("{}", text));
 }
 
-unsafe fn alloc(ptr: &mut *mut u8, layout: core::alloc::Layout) {
-    *ptr = crate::heap::HEAP.alloc_internal(layout);
+unsafe fn alloc(layout: Layout) -> *mut u8 {
+    crate::heap::HEAP.alloc_internal(layout)
 }
 
-unsafe fn dealloc(ptr: *mut u8, layout: core::alloc::Layout) {
+unsafe fn dealloc(ptr: *mut u8, layout: Layout) {
     crate::heap::HEAP.dealloc_internal(ptr, layout);
 }
 
-fn is_key_pressed(out: &mut bool, key: crate::keyboard::Key) {
-    *out = crate::keyboard::is_key_pressed(key);
+unsafe fn run_program(program: &[u8]) -> Result<Pid, Errno> {
+    Ok(crate::execution::run_program(program)?)
 }
 
+fn disable_interrupts() { interrupts::set_for_caller(false) }
+
+fn enable_interrupts() { interrupts::set_for_caller(true) }
+
 fn empty() {}
 
 fn halt() { unsafe { asm!("hlt"); } }
 
+/// Exec's arguments are references themselves, so they need checking too
+fn exec_syscall(path: &str, argv: &[&str], envp: &[&str]) -> Result<(), Errno> {
+    for &arg in argv.iter().chain(envp) {
+        <&str>::check(&[arg.as_ptr() as u32, arg.len() as u32])?;
+    }
+    Ok(crate::execution::exec(path, argv, envp)?)
+}
+
+/// The vectors get pushed to, so the memory they already own has to be the caller's as well
+fn files_in_dir(root: &str, folders: &mut Vec<String>, files: &mut Vec<crate::fs::FileMetadata>) -> Result<(), Errno> {
+    check_ptr::<String>(folders.as_ptr() as u32, folders.capacity() as u32, true)?;
+    check_ptr::<crate::fs::FileMetadata>(files.as_ptr() as u32, files.capacity() as u32, true)?;
+    for folder in folders.iter() {
+        check_ptr::<u8>(folder.as_ptr() as u32, folder.capacity() as u32, true)?;
+    }
+    Ok(crate::fs::dir(root, folders, files)?)
+}
+
+unsafe fn read_sectors(lba: u32, buffer: *mut u8, sector_count: usize) -> Result<(), Errno> {
+    uaccess::check_range(buffer as usize, sector_count.checked_mul(512).ok_or(Errno::EFAULT)?, true)?;
+    crate::ata::read_sectors(lba, buffer, sector_count);
+    Ok(())
+}
+
+unsafe fn write_sectors(lba: u32, data: *const u8, sector_count: usize) -> Result<(), Errno> {
+    uaccess::check_range(data as usize, sector_count.checked_mul(512).ok_or(Errno::EFAULT)?, false)?;
+    crate::ata::write_sectors(lba, data, sector_count);
+    Ok(())
+}
+
 unsafe fn set_isr(index: usize, func: extern "x86-interrupt" fn(), dpl: u8) {
     crate::interrupts::IDT[index] = crate::interrupts::Handler::new(func, interrupts::GateType::DInterrupt, dpl);
 }
 
-macro_rules! generate_ret_func {
-    ($name: ident, $expr: expr, $type: ty) => {
-        fn $name(out: &mut $type) {
-            *out = $expr;
-        }
-    };
+fn get_on_key_down() -> &'static Mutex<Event<crate::keyboard::KeyArgs>> { &crate::keyboard::ON_KEY_DOWN }
+fn get_on_key_up() -> &'static Mutex<Event<crate::keyboard::KeyArgs>> { &crate::keyboard::ON_KEY_UP }
+fn get_console() -> &'static Lazy<Mutex<crate::vga_console::Console>> { &crate::vga_console::CONSOLE }
+fn fs_get_header() -> &'static Lazy<Mutex<&'static mut crate::fs::Header>> { &crate::fs::HEADER }
+// (Writing to out might fault its page in, which needs FRAMES_USAGE, so we let go of it first)
+fn mem_get_info(out: *mut crate::paging::MemInfo) -> Result<(), Errno> {
+    let info = crate::paging::FRAMES_USAGE.lock().info();
+    uaccess::copy_to_user(out, info)
+}
+fn tick_counts(out: *mut crate::timer::TickCounts) -> Result<(), Errno> { uaccess::copy_to_user(out, crate::timer::get_tick_counts()) }
+
+/// Waits until the child process pid exits, and returns its exit status (or ECHILD if there is no such child)
+pub fn wait(pid: Pid) -> Result<i32, Errno> {
+    let mut status = 0;
+    WaitPid::call(pid, &mut status).map(|_| status)
+}
+
+/// Creates a copy of the calling process. Returns the child's PID in the parent, and 0 in the child
+pub fn fork() -> Pid {
+    Fork::call()
+}
+
+/// Replaces the calling process' program with the ELF executable at path, passing it argv and envp.
+/// Only returns if that fails
+pub fn exec(path: &str, argv: &[&str], envp: &[&str]) -> Errno {
+    match Exec::call(path, argv, envp) {
+        Ok(()) => unreachable!(),
+        Err(err) => err,
+    }
+}
+
+/// Starts a new thread in the calling process, which runs entry(arg) on stack (the top of the memory it should use).
+/// Returns its thread ID, for thread_join (or EFAULT if stack isn't writable memory)
+pub fn thread_create(entry: ThreadEntry, stack: *mut u8, arg: usize) -> Result<Pid, Errno> {
+    ThreadCreate::call(entry, stack, arg)
 }
 
-generate_ret_func!(has_init_heap, crate::heap::has_init(), bool);
-generate_ret_func!(has_loaded_processes, crate::process::has_loaded_processes(), bool);
-generate_ret_func!(get_curr_page_dir, crate::paging::PageDirectory::curr(), *mut crate::paging::PageDirectory);
-generate_ret_func!(are_interrupts_enabled, crate::interrupts::is_enabled(), bool);
-generate_ret_func!(get_on_key_down, &crate::keyboard::ON_KEY_DOWN, &'static Mutex<Event<crate::keyboard::KeyArgs>>);
-generate_ret_func!(get_on_key_up, &crate::keyboard::ON_KEY_UP, &'static Mutex<Event<crate::keyboard::KeyArgs>>);
-generate_ret_func!(get_console, &crate::vga_console::CONSOLE, &'static Lazy<Mutex<crate::vga_console::Console>>);
-generate_ret_func!(is_caps_lock_active, crate::keyboard::is_caps_lock_active(), bool);
-generate_ret_func!(fs_get_header, &crate::fs::HEADER, &'static Lazy<Mutex<&'static mut crate::fs::Header>>);
-
-#[allow(invalid_value)] // out's initial value is discarded. Giving it an actual value would be a massive waste of performance.
-pub fn get_fs_header() -> &'static Lazy<Mutex<&'static mut crate::fs::Header>>{
-    let mut out = unsafe { core::mem::transmute(0) };
-    FsGetHeader::call(&mut out);
+/// Waits until the thread tid (of the calling process) exits, and returns its exit status (or None if there is no such thread)
+pub fn thread_join(tid: Pid) -> Option<i32> {
+    let mut status = 0;
+    ThreadJoin::call(tid, &mut status).ok().map(|_| status)
+}
+
+/// Blocks until someone calls futex_wake on addr, unless it doesn't hold expected anymore.
+/// Returns false if it didn't block. (Callers should check whatever they were waiting for again either way)
+pub fn futex_wait(addr: &core::sync::atomic::AtomicU32, expected: u32) -> bool {
+    FutexWait::call(addr as *const _ as *const u32, expected).is_ok()
+}
+
+/// Wakes up to n threads waiting on addr, and returns how many there were
+pub fn futex_wake(addr: &core::sync::atomic::AtomicU32, n: usize) -> usize {
+    FutexWake::call(addr as *const _ as *const u32, n)
+}
+
+/// Opens the file at path, creating it first if create is set and it doesn't exist. Returns its descriptor
+pub fn open(path: &str, create: bool) -> Result<Fd, Errno> {
+    Open::call(path, create)
+}
+
+/// Moves fd's cursor to pos, counting from the start of the file
+pub fn seek(fd: Fd, pos: usize) -> Result<(), Errno> {
+    Seek::call(fd, pos)
+}
+
+/// Returns a new descriptor (the lowest free one) for whatever fd refers to
+pub fn dup(fd: Fd) -> Result<Fd, Errno> {
+    Dup::call(fd)
+}
+/// Makes new_fd (which has to be below fd::MAX_FDS) refer to whatever fd does (closing what it referred to before), and returns it
+/// Makes new_fd refer to whatever fd does (closing what it referred to before), and returns it
+pub fn dup2(fd: Fd, new_fd: Fd) -> Result<Fd, Errno> {
+    Dup2::call(fd, new_fd)
+}
+
+/// Runs the ELF executable open at fd as a child process, and returns its PID
+pub fn execute_file(fd: Fd) -> Result<Pid, Errno> {
+    ExecuteFile::call(fd)
+}
+
+/// Creates a pipe, and returns descriptors for its read end and write end (in that order)
+pub fn pipe() -> Result<[Fd; 2], Errno> {
+    let mut out = [0; 2];
+    Pipe::call(&mut out).map(|_| out)
+}
+
+/// Reads up to buffer.len() bytes from fd, blocking until there's something to read. Returns 0 at EOF
+pub fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize, Errno> {
+    Read::call(fd, buffer)
+}
+
+/// Writes all of data to fd, blocking until there's room for it
+pub fn write(fd: Fd, data: &[u8]) -> Result<usize, Errno> {
+    Write::call(fd, data)
+}
+
+pub fn close(fd: Fd) -> Result<(), Errno> {
+    Close::call(fd)
+}
+
+pub fn get_mem_info() -> crate::paging::MemInfo {
+    let mut out = crate::paging::MemInfo::default();
+    GetMemInfo::call(&mut out).unwrap();
     out
 }
+
+/// Sets the nice value of pid (the calling process or one of its children). Returns false if there's no such process,
+/// or if it would make pid less nice and the calling process isn't privileged
+pub fn set_nice(pid: Pid, nice: i32) -> bool {
+    SetNice::call(pid, nice).is_ok()
+}
+
+/// Returns how many ticks pid has spent running, or None if there's no such process
+pub fn get_process_times(pid: Pid) -> Option<ProcessTimes> {
+    let mut out = ProcessTimes::default();
+    GetProcessTimes::call(pid, &mut out).ok().map(|_| out)
+}
+
+pub fn get_tick_counts() -> crate::timer::TickCounts {
+    let mut out = crate::timer::TickCounts::default();
+    GetTickCounts::call(&mut out).unwrap();
+    out
+}
+
+pub fn get_fs_header() -> &'static Lazy<Mutex<&'static mut crate::fs::Header>> {
+    FsGetHeader::call()
+}
+
+/// Turns syscall tracing on or off for pid (the calling process or one of its children), and for whatever processes it
+/// creates from then on. Returns false if there's no such process
+pub fn set_trace(pid: Pid, enabled: bool) -> bool {
+    SetTrace::call(pid, enabled).is_ok()
+}
+
+/// Takes up to buffer.len() bytes out of the kernel log (e.g. syscall traces), the oldest ones first. Returns how many there were
+pub fn read_log(buffer: &mut [u8]) -> usize {
+    ReadLog::call(buffer)
+}
+
+/// Creates an empty directory at path (relative to the working directory, unless it starts with a /)
+pub fn mkdir(path: &str) -> Result<(), Errno> {
+    Mkdir::call(path)
+}
+
+/// Removes the directory at path, which has to be empty (and not any process' working directory, or it fails with EBUSY)
+pub fn rmdir(path: &str) -> Result<(), Errno> {
+    Rmdir::call(path)
+}
+
+/// Writes the entries of the directory at path to out (as many as fit), and returns how many it has in total
+pub fn readdir(path: &str, out: &mut [crate::fs::DirEntry]) -> Result<usize, Errno> {
+    Readdir::call(path, out)
+}
+
+/// Makes the directory at path the working directory of the calling process (and of whatever processes it creates after)
+pub fn chdir(path: &str) -> Result<(), Errno> {
+    Chdir::call(path)
+}
+
+/// Writes the absolute path of the calling process' working directory to buffer, and returns it
+pub fn getcwd(buffer: &mut [u8]) -> Result<&str, Errno> {
+    let len = Getcwd::call(buffer)?;
+    Ok(core::str::from_utf8(&buffer[..len]).unwrap_or("/"))
+}
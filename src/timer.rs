@@ -1,84 +1,124 @@
-use crate::interrupts::GateType;
+use crate::interrupts::{GateType, TrapFrame};
 use crate::{interrupts, pic};
-use alloc::boxed::Box;
 use spin::Mutex;
 use crate::events::{Event, EventHandler};
 use core::arch::asm;
-use crate::paging::PageDirectory;
 
 pub fn init() {
     unsafe {
         // attach on_tick to IRQ0
         interrupts::IDT[pic::IRQ_OFFSET + 0] =
             interrupts::Handler::new_raw(on_tick as u32, GateType::DInterrupt, 0);
+        // Only the kernel may give up the CPU this way (user programs use the Yield syscall)
+        interrupts::IDT[crate::process::YIELD_VECTOR] =
+            interrupts::Handler::new_raw(on_yield as u32, GateType::DInterrupt, 0);
     }
 }
 
+/// The PIT's input frequency, in Hz
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// We leave the PIT at its default divisor, so it ticks about 18.2 times a second
+const PIT_DIVISOR: u64 = 65536;
+
+/// The number of ticks it takes for at least ms milliseconds to pass
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * PIT_FREQUENCY).div_ceil(PIT_DIVISOR * 1000)
+}
+
+// Saves the interrupted code's state as a TrapFrame, just like the syscall handlers (see syscall.rs).
+// on_tick_internal returns the frame to resume: either the same one, or another process' (on its own kernel stack).
 #[naked]
 extern "x86-interrupt" fn on_tick() {
     unsafe {
         asm!(
-            "pushfd",
             "pushad",
-            "sub esp, 1024",
+            "push ds",
+            "push es",
+            "push fs",
+            "push gs",
+            "push esp", // the TrapFrame we just pushed
             "call on_tick_internal",
-            "add esp, 1024",
+            "mov esp, eax", // the frame to resume, possibly on another process' kernel stack
+            "pop gs",
+            "pop fs",
+            "pop es",
+            "pop ds",
             "popad",
-            "popfd",
             "iretd",
             options(noreturn)
         );
     }
 }
 
-#[allow(named_asm_labels)]
 #[no_mangle]
-fn on_tick_internal() {
-    // Force preservation of state.
-    // Even though the "x86-interrupt" ABI preserves all used registers, some registers are not actually used
-    // by the function. So they won't be preserved. Which is bad.
-    // This causes some registers to be pushed twice, but there doesn't seem to be a simple fix.
-
-    crate::interrupts::disable();
-    unsafe { TIMER += 1; }
+extern "C" fn on_tick_internal(frame: *mut TrapFrame) -> *mut TrapFrame {
+    unsafe {
+        TIMER += 1;
+        if crate::process::is_idle() {
+            IDLE_TICKS += 1;
+        }
+    }
     ON_TICK.lock().invoke(());
-
-    // If we haven't yet initialised the heap, don't run the task scheduler code
-    if !crate::heap::has_init() || !crate::process::has_loaded_processes() {
-        pic::send_eoi(0);
-        crate::interrupts::enable();
-        return;
+    if crate::process::has_loaded_processes() {
+        crate::process::wake_sleepers(get_ticks());
     }
 
-    let context = Box::new(crate::process::Context { esp: 0, eip: 0, dir: core::ptr::null_mut() });
-    let context_ptr = Box::into_raw(context);
-    unsafe {
-        asm!(
-            "mov [ebx], esp", // undo push
-            "lea eax, end_of_on_tick",
-            "mov [ebx+4], eax",
-            "mov [ebx+8], ecx",
-            in("ebx") context_ptr,
-            in("ecx") PageDirectory::curr(),
-            out("eax") _ // clobber
-        );
-    }
+    pic::send_eoi(0);
 
-    crate::process::next_program(context_ptr); // jumps out of this function
+    // If we haven't yet initialised the heap, don't run the task scheduler code.
+    // Every process has its own kernel stack, so it doesn't matter whether we interrupted it in user mode or not.
+    if crate::heap::has_init() && crate::process::has_loaded_processes() {
+        crate::process::tick(frame)
+    } else {
+        frame
+    }
+}
 
+// Exactly like on_tick, except that it doesn't count as a tick
+#[naked]
+extern "x86-interrupt" fn on_yield() {
     unsafe {
         asm!(
-            ".global end_of_on_tick",
-            "end_of_on_tick:",
+            "pushad",
+            "push ds",
+            "push es",
+            "push fs",
+            "push gs",
+            "push esp", // the TrapFrame we just pushed
+            "call on_yield_internal",
+            "mov esp, eax", // the frame to resume, possibly on another process' kernel stack
+            "pop gs",
+            "pop fs",
+            "pop es",
+            "pop ds",
+            "popad",
+            "iretd",
+            options(noreturn)
         );
-        pic::set_mask(0, false);
-        pic::send_eoi(0);
-        crate::interrupts::enable();
     }
 }
 
+#[no_mangle]
+extern "C" fn on_yield_internal(frame: *mut TrapFrame) -> *mut TrapFrame {
+    crate::process::next_program(frame)
+}
+
 #[inline]
 pub fn get_ticks() -> u64 { unsafe { TIMER } }
 
+/// How the ticks since boot were spent
+#[derive(Clone, Copy, Default, Debug)]
+pub struct TickCounts {
+    /// Ticks where no process was ready, so the CPU was halted
+    pub idle: u64,
+    /// Every other tick
+    pub busy: u64,
+}
+
+pub fn get_tick_counts() -> TickCounts {
+    unsafe { TickCounts { idle: IDLE_TICKS, busy: TIMER - IDLE_TICKS } }
+}
+
 pub static ON_TICK: Mutex<Event<()>> = Mutex::new(Event::<>::new());
 static mut TIMER: u64 = 0;
+static mut IDLE_TICKS: u64 = 0;
@@ -0,0 +1,54 @@
+use core::mem::size_of;
+
+use crate::{errno::Errno, paging::{PageDirectory, PageFlags, HEAP_LIMIT, PAGE_SIZE}};
+
+/// Where the page tables are mapped (see PageDirectory::get_table_ptr). They're never a syscall argument
+const PAGE_TABLES_START: usize = 0xFF800000;
+
+/// Makes sure the user program that made the current syscall may access len bytes at addr (see check_user).
+/// Pointers from the kernel itself are always fine
+pub(crate) fn check_range(addr: usize, len: usize, write: bool) -> Result<(), Errno> {
+    if !crate::syscall::from_user() {
+        return Ok(());
+    }
+    check_user(addr, len, write)
+}
+
+/// Makes sure a user program may access len bytes at addr (and write them, if write is set). Every page they're on has to
+/// be mapped as user memory in the active directory, or belong to one of its areas (see vma), and then it'll be mapped once
+/// it's touched. Otherwise it's EFAULT. Everything below HEAP_LIMIT (the kernel's heap) is off limits no matter how it's mapped
+pub(crate) fn check_user(addr: usize, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len).ok_or(Errno::EFAULT)?;
+    if addr < HEAP_LIMIT || end > PAGE_TABLES_START {
+        return Err(Errno::EFAULT);
+    }
+
+    let dir = PageDirectory::curr();
+    for page_addr in (addr & !(PAGE_SIZE - 1)..end).step_by(PAGE_SIZE) {
+        let allowed = match unsafe { (*dir).get_page(page_addr) } {
+            // Copy on write pages are read only until they're first written to, but writing to them is fine
+            Some(page) if page.present() => page.user() && (!write || page.rw() || page.cow()),
+            _ => crate::vma::area_flags(dir, page_addr).is_some_and(|flags| !write || flags.contains(PageFlags::RW)),
+        };
+        if !allowed {
+            return Err(Errno::EFAULT);
+        }
+    }
+    Ok(())
+}
+
+/// Reads a T from src, which the caller of the current syscall passed us
+pub(crate) fn copy_from_user<T: Copy>(src: *const T) -> Result<T, Errno> {
+    check_range(src as usize, size_of::<T>(), false)?;
+    Ok(unsafe { src.read_unaligned() })
+}
+
+/// Writes value to dst, which the caller of the current syscall passed us
+pub(crate) fn copy_to_user<T>(dst: *mut T, value: T) -> Result<(), Errno> {
+    check_range(dst as usize, size_of::<T>(), true)?;
+    unsafe { dst.write_unaligned(value) };
+    Ok(())
+}
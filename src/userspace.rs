@@ -1,5 +1,6 @@
 use core::{mem::size_of, arch::asm};
-use crate::{DATA_SEG, KERNEL_STACK_TOP};
+use spin::Lazy;
+use crate::{CODE_SEG, DATA_SEG, KERNEL_STACK_TOP, interrupts::TrapFrame};
 
 #[repr(C, packed)]
 struct GdtPtr {
@@ -20,10 +21,11 @@ struct GdtEntry {
 pub(crate) fn init() {
     unsafe {
         let gdt = GdtPtr {
-            limit: 47, // size of a GDT entry * number of entries - 1
+            limit: 55, // size of a GDT entry * number of entries - 1
             base: &GDT_ENTRIES_ADDR as *const _ as u32,
         };
         write_tss();
+        write_double_fault_tss();
         asm!("lgdt [{gdt_ptr}]", gdt_ptr = in(reg) &gdt);
         tss_flush();
     }
@@ -92,27 +94,131 @@ unsafe fn write_tss() {
     TSS_ENTRY.esp0 = &KERNEL_STACK_TOP as *const _ as u32;
 }
 
+/// The task the CPU switches to on a double fault (see interrupts::double_fault_task)
+static mut DOUBLE_FAULT_TSS: TssEntry = TssEntry {prev_tss: 0, esp0: 0, ss0: 0, esp1: 0, ss1: 0, esp2: 0, ss2: 0, cr3: 0, eip: 0, eflags: 0, eax: 0, ecx: 0, edx: 0, ebx: 0, esp: 0, ebp: 0, esi: 0, edi: 0, es: 0, cs: 0, ss: 0, ds: 0, fs: 0, gs: 0, ldt: 0, trap: 0, iomap_base: 0 };
+/// The double fault task's own stack. Double faults mostly come from running out of some other stack, so it can't use that
+static mut DOUBLE_FAULT_STACK: [u8; 4096] = [0; 4096];
+
+unsafe fn write_double_fault_tss() {
+    let base = &DOUBLE_FAULT_TSS as *const _ as u32;
+    let limit = size_of::<TssEntry>() as u32;
+
+    // 0x89: present, ring 0, available 32 bit TSS. Only the double fault's task gate can switch to it
+    gdt_set_gate(&GDT_ENTRIES_ADDR as *const u32 as *mut GdtEntry, 6, base, limit, 0x89, 0);
+
+    let code = &CODE_SEG as *const _ as u32;
+    let data = &DATA_SEG as *const _ as u32;
+    DOUBLE_FAULT_TSS.eip = crate::interrupts::double_fault_task as usize as u32;
+    DOUBLE_FAULT_TSS.esp = DOUBLE_FAULT_STACK.as_ptr() as u32 + DOUBLE_FAULT_STACK.len() as u32;
+    DOUBLE_FAULT_TSS.eflags = 0x2; // interrupts disabled, and bit 1 which is always set
+    DOUBLE_FAULT_TSS.cs = code;
+    (DOUBLE_FAULT_TSS.ss, DOUBLE_FAULT_TSS.ds, DOUBLE_FAULT_TSS.es) = (data, data, data);
+    (DOUBLE_FAULT_TSS.fs, DOUBLE_FAULT_TSS.gs) = (data, data);
+}
+
+/// Sets the directory the double fault task runs in. It has to map the kernel, and stay around (see paging::init)
+pub(crate) fn set_double_fault_dir(dir: u32) {
+    unsafe { DOUBLE_FAULT_TSS.cr3 = dir; }
+}
+
+/// The selector of the double fault task's TSS, for its task gate
+pub(crate) fn double_fault_tss_selector() -> u16 {
+    unsafe { &DOUBLE_FAULT_TSS_SEG as *const _ as u16 }
+}
+
+/// Where the task that double faulted was (its eip and esp), as the CPU saved it when it switched to the double fault task
+pub(crate) fn faulted_task_state() -> (u32, u32) {
+    unsafe { (TSS_ENTRY.eip, TSS_ENTRY.esp) }
+}
+
 extern "C" {
     static USER_CODE_SEG: u32;
+    static USER_DATA_SEG: u32;
+    static DOUBLE_FAULT_TSS_SEG: u32;
     static GDT_ENTRIES_ADDR: u32;
 }
 
-/// Enters userspace
-pub(crate) unsafe fn enter() {
-    asm!(
-        "mov ax, (4 * 8) | 3", // user data segment with RPL 3
-        "mov ds, ax",
-        "mov es, ax",
-        "mov fs, ax",
-        "mov gs, ax", // sysexit sets SS
-        "xor edx, edx", // not necessary; set to 0
-        "mov eax, edi",  
-        "mov ecx, 0x174", // MSR specifier: IA32_SYSENTER_CS
-        "wrmsr", // set sysexit segments
-        "lea edx, 5f", // to be loaded into EIP
-        "mov ecx, esp", // to be loaded into ESP
-        "sysexit",
-        "5:",
-        in("edi") &USER_CODE_SEG as *const _ as i32 - 16 // new CS=edi+16, new SS=edi+24
-    );
+// The MSRs sysenter takes the kernel's code segment, stack and entry point from.
+// (sysenter and sysexit figure out the rest of the segments from the code segment, which is why the GDT is in the order it is)
+const IA32_SYSENTER_CS: u32 = 0x174;
+const IA32_SYSENTER_ESP: u32 = 0x175;
+const IA32_SYSENTER_EIP: u32 = 0x176;
+
+unsafe fn wrmsr(msr: u32, value: u32) {
+    asm!("wrmsr", in("ecx") msr, in("eax") value, in("edx") 0);
+}
+
+static HAS_SYSENTER: Lazy<bool> = Lazy::new(|| {
+    let (signature, features): (u32, u32);
+    unsafe { asm!("cpuid", inout("eax") 1 => signature, out("ebx") _, out("ecx") _, out("edx") features); }
+    let (family, model, stepping) = ((signature >> 8) & 0xF, (signature >> 4) & 0xF, signature & 0xF);
+    // The Pentium Pro claims to have them, but doesn't
+    features & (1 << 11) != 0 && !(family == 6 && model < 3 && stepping < 3)
+});
+
+/// Whether the CPU has sysenter and sysexit, so that syscalls can skip the IDT (see syscall::raw_syscall)
+pub fn has_sysenter() -> bool { *HAS_SYSENTER }
+
+/// Whether we're running in ring 3
+pub fn in_user_mode() -> bool {
+    let cs: u32;
+    unsafe { asm!("mov {:e}, cs", out(reg) cs); }
+    cs & 3 == 3
+}
+
+/// Makes sysenter jump to entry, in ring 0 and on the kernel stack (see set_kernel_stack). The CPU must have it
+pub(crate) unsafe fn init_sysenter(entry: u32) {
+    wrmsr(IA32_SYSENTER_CS, &CODE_SEG as *const _ as u32);
+    wrmsr(IA32_SYSENTER_ESP, TSS_ENTRY.esp0);
+    wrmsr(IA32_SYSENTER_EIP, entry);
+}
+
+/// Sets the stack the CPU switches to when going from user mode to ring 0, be it through an interrupt or sysenter.
+/// (There's only one CPU, so there's only one of each to set)
+pub(crate) fn set_kernel_stack(top: u32) {
+    unsafe {
+        TSS_ENTRY.esp0 = top;
+        if has_sysenter() {
+            wrmsr(IA32_SYSENTER_ESP, top);
+        }
+    }
+}
+
+/// Returns the code and data selectors user programs run with
+pub(crate) fn user_segments() -> (u32, u32) {
+    // The selectors are the segments' offsets in the GDT, with RPL 3
+    let code = unsafe { &USER_CODE_SEG as *const _ as u32 } | 3;
+    let data = unsafe { &USER_DATA_SEG as *const _ as u32 } | 3;
+    (code, data)
+}
+
+/// Returns a frame that starts executing eip in user mode, with the given stack and interrupts enabled.
+/// (Like any other frame, it's restored by popping it and doing an iretd)
+pub(crate) fn user_frame(eip: u32, esp: u32) -> TrapFrame {
+    let (code, data) = user_segments();
+    TrapFrame {
+        gs: data, fs: data, es: data, ds: data,
+        edi: 0, esi: 0, ebp: 0, kernel_esp: 0, ebx: 0, edx: 0, ecx: 0, eax: 0,
+        eip,
+        cs: code,
+        eflags: 0x202, // IF, and bit 1 which is always set
+        user_esp: esp,
+        user_ss: data,
+    }
+}
+
+/// Returns a frame that starts executing eip in ring 0, with interrupts enabled.
+/// iretd doesn't switch stacks within the same ring, so it keeps running on whatever stack the frame was popped from.
+pub(crate) fn kernel_frame(eip: u32) -> TrapFrame {
+    let code = unsafe { &CODE_SEG as *const _ as u32 };
+    let data = unsafe { &DATA_SEG as *const _ as u32 };
+    TrapFrame {
+        gs: data, fs: data, es: data, ds: data,
+        edi: 0, esi: 0, ebp: 0, kernel_esp: 0, ebx: 0, edx: 0, ecx: 0, eax: 0,
+        eip,
+        cs: code,
+        eflags: 0x202, // IF, and bit 1 which is always set
+        user_esp: 0,
+        user_ss: 0,
+    }
 }
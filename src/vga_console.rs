@@ -98,15 +98,7 @@ impl io::Write for Console {
         self.update_cursor();
     }
     fn write_string(&mut self, str: &str) {
-        for byte in str.bytes() {
-            match byte {
-                0x20..=0x7E => self.buffer.write(&mut self.ptr, byte, self.color),
-                b'\n' => self.newline_raw(),
-                b'\t' => self.write_string("    "),
-                _ => self.buffer.write(&mut self.ptr, b'?', self.color),
-            }
-        }
-        self.update_cursor();
+        self.write_text(str.as_bytes());
     }
 }
 
@@ -147,6 +139,19 @@ impl io::Read for Console {
 }
 
 impl Console {
+    /// Writes bytes as text: newlines and tabs do what they should, and anything else that isn't printable shows up as '?'
+    pub fn write_text(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            match byte {
+                0x20..=0x7E => self.buffer.write(&mut self.ptr, byte, self.color),
+                b'\n' => self.newline_raw(),
+                b'\t' => self.write_text(b"    "),
+                _ => self.buffer.write(&mut self.ptr, b'?', self.color),
+            }
+        }
+        self.update_cursor();
+    }
+
     #[inline]
     pub fn set_color(&mut self, color: ColorCode) {
         self.color = color;
@@ -229,9 +234,23 @@ pub fn _print(args: fmt::Arguments) {
     }
 }
 
+/// Formats args and prints the result through the Print syscall. The formatting happens here, in the caller,
+/// so the kernel never has to run a user program's code
+#[doc(hidden)]
+pub fn _print_syscall(args: fmt::Arguments) {
+    struct PrintSyscall;
+    impl fmt::Write for PrintSyscall {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            crate::syscall::Print::call(s);
+            Ok(())
+        }
+    }
+    fmt::Write::write_fmt(&mut PrintSyscall, args).unwrap();
+}
+
 #[macro_export]
 macro_rules! print {
-    ($($arg:tt)*) => ($crate::syscall::Print::call(format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::vga_console::_print_syscall(format_args!($($arg)*)));
 }
 
 #[macro_export]
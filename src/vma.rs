@@ -0,0 +1,132 @@
+use alloc::{collections::BTreeMap, sync::Arc, vec::Vec};
+use spin::Mutex;
+
+use crate::paging::{PageDirectory, PageFlags, FRAMES_USAGE, PAGE_SIZE};
+
+/// The bytes a file backed area starts with.
+#[derive(Clone)]
+pub struct AreaData {
+    /// The whole file the data comes from (e.g. an ELF image). Shared between every area that uses it.
+    pub image: Arc<[u8]>,
+    /// Where in image the data starts
+    pub offset: usize,
+    /// How many bytes of image to use. Anything past them is zeroed
+    pub len: usize,
+    /// The virtual address the data should be at (doesn't have to be page aligned)
+    pub addr: usize,
+}
+
+/// A virtual memory area: a range of a process' address space that is only backed by frames
+/// once it's actually touched (see handle_fault).
+#[derive(Clone)]
+pub struct Area {
+    /// The first page of the area
+    pub start: usize,
+    /// The first page after the area
+    pub end: usize,
+    pub flags: PageFlags,
+    /// None for anonymous (zero filled) memory
+    pub data: Option<AreaData>,
+}
+
+impl Area {
+    /// An area covering addr..addr+size, expanded to whole pages
+    pub fn new(addr: usize, size: usize, flags: PageFlags, data: Option<AreaData>) -> Self {
+        Area {
+            start: addr & !(PAGE_SIZE - 1),
+            end: (addr + size).next_multiple_of(PAGE_SIZE),
+            flags,
+            data,
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end).contains(&addr)
+    }
+
+    /// Copies whatever part of the area's data belongs to the (already mapped) page at page_addr into it
+    unsafe fn fill(&self, page_addr: usize) {
+        let Some(data) = &self.data else { return; };
+
+        let start = usize::max(page_addr, data.addr);
+        let end = usize::min(page_addr + PAGE_SIZE, data.addr + data.len);
+        if start >= end {
+            return; // this page is entirely .bss (or whatever else comes after the data)
+        }
+
+        core::ptr::copy_nonoverlapping(
+            data.image.as_ptr().add(data.offset + (start - data.addr)),
+            start as *mut u8,
+            end - start,
+        );
+    }
+}
+
+/// The areas of every address space, keyed by the address of its page directory
+static AREAS: Mutex<BTreeMap<usize, Vec<Area>>> = Mutex::new(BTreeMap::new());
+
+/// Records area as part of dir's address space. Nothing is mapped until it's touched.
+pub(crate) fn add_area(dir: *mut PageDirectory, area: Area) {
+    AREAS.lock().entry(dir as usize).or_default().push(area);
+}
+
+/// Gives child a copy of every area of parent (see PageDirectory::fork)
+pub(crate) fn clone_areas(parent: *mut PageDirectory, child: *mut PageDirectory) {
+    let mut areas = AREAS.lock();
+    let copy = areas.get(&(parent as usize)).cloned().unwrap_or_default();
+    areas.insert(child as usize, copy);
+}
+
+/// Forgets every area of dir, and frees whatever frames were backing them
+pub(crate) unsafe fn free_areas(dir: *mut PageDirectory) {
+    let areas = AREAS.lock().remove(&(dir as usize)).unwrap_or_default();
+    for area in areas {
+        for page_addr in (area.start..area.end).step_by(PAGE_SIZE) {
+            (*dir).free_page(page_addr);
+        }
+    }
+}
+
+/// Returns the permissions the page at page_addr gets once it's touched, or None if it isn't part of any of areas.
+/// Areas don't have to be page aligned, so a single page can belong to several of them (e.g. the end of .text
+/// and the start of .data). The page gets the permissions of all of them.
+fn flags_of(areas: &[Area], page_addr: usize) -> Option<PageFlags> {
+    let mut flags = None;
+    for area in areas.iter().filter(|a| a.contains(page_addr)) {
+        flags = Some(flags.unwrap_or(PageFlags::NONE) | area.flags);
+    }
+    flags
+}
+
+/// Returns the permissions the page at page_addr of dir gets once it's touched, or None if it isn't part of any of its areas
+pub(crate) fn area_flags(dir: *mut PageDirectory, page_addr: usize) -> Option<PageFlags> {
+    flags_of(AREAS.lock().get(&(dir as usize))?, page_addr)
+}
+
+/// Called on a page fault for a non-present page in the active directory.
+/// If addr belongs to one of the directory's areas, the page is mapped to a fresh frame and filled, and true is returned.
+/// Otherwise the access is simply invalid, and false is returned.
+pub(crate) fn handle_fault(addr: usize) -> bool {
+    let dir = PageDirectory::curr();
+    let areas = AREAS.lock();
+    let Some(areas) = areas.get(&(dir as usize)) else { return false; };
+
+    let page_addr = addr & !(PAGE_SIZE - 1);
+    let Some(flags) = flags_of(areas, page_addr) else { return false; };
+
+    unsafe {
+        let frame: usize = FRAMES_USAGE.lock().get_free_frame();
+        // CR0.WP is on, so the page has to be writable while we fill it, even if it's read only afterwards
+        (*dir).make_page(page_addr, frame * PAGE_SIZE, flags | PageFlags::RW | PageFlags::USER).unwrap();
+
+        core::ptr::write_bytes(page_addr as *mut u8, 0, PAGE_SIZE);
+        for area in areas.iter().filter(|a| a.contains(page_addr)) {
+            area.fill(page_addr);
+        }
+
+        if !flags.contains(PageFlags::RW) {
+            (*dir).set_writable(page_addr, false);
+        }
+    }
+    true
+}